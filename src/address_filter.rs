@@ -0,0 +1,114 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+use zeronet_protocol::PeerAddr as Address;
+
+/// Ranges that a peer address can fall into, determining
+/// whether it is useful to hand out to other peers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddressClass {
+  Public,
+  /// 127.0.0.0/8 and ::1
+  Loopback,
+  /// RFC1918 ranges and IPv6 unique local addresses (fc00::/7)
+  Private,
+  /// 169.254.0.0/16 and fe80::/10
+  LinkLocal,
+  /// Carrier-grade NAT range 100.64.0.0/10
+  Shared,
+  /// Unspecified, multicast, broadcast, documentation and other reserved
+  /// ranges, which are never accepted.
+  Reserved,
+}
+
+impl AddressClass {
+  /// Names of the classes that can be allowed through configuration.
+  pub const ALLOWABLE: [&'static str; 4] = ["loopback", "private", "link-local", "shared"];
+}
+
+impl FromStr for AddressClass {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<AddressClass, String> {
+    match s {
+      "loopback" => Ok(AddressClass::Loopback),
+      "private" => Ok(AddressClass::Private),
+      "link-local" => Ok(AddressClass::LinkLocal),
+      "shared" => Ok(AddressClass::Shared),
+      _ => Err(format!("'{}' is not an allowable address class.", s)),
+    }
+  }
+}
+
+pub fn classify_ipv4(ip: Ipv4Addr) -> AddressClass {
+  match ip.octets() {
+    [127, ..] => AddressClass::Loopback,
+    [10, ..] | [172, 16..=31, ..] | [192, 168, ..] => AddressClass::Private,
+    [169, 254, ..] => AddressClass::LinkLocal,
+    [100, 64..=127, ..] => AddressClass::Shared,
+    [0, ..]
+    | [192, 0, 0, _]
+    | [192, 0, 2, _]
+    | [198, 51, 100, _]
+    | [198, 18..=19, ..]
+    | [203, 0, 113, _]
+    | [224..=255, ..] => AddressClass::Reserved,
+    _ => AddressClass::Public,
+  }
+}
+
+pub fn classify_ipv6(ip: Ipv6Addr) -> AddressClass {
+  if let Some(ip) = ipv4_mapped(&ip) {
+    return classify_ipv4(ip);
+  }
+
+  let segments = ip.segments();
+  if ip.is_loopback() {
+    AddressClass::Loopback
+  } else if ip.is_unspecified() || ip.is_multicast() || segments[..2] == [0x2001, 0xdb8] {
+    AddressClass::Reserved
+  } else if segments[0] & 0xfe00 == 0xfc00 {
+    AddressClass::Private
+  } else if segments[0] & 0xffc0 == 0xfe80 {
+    AddressClass::LinkLocal
+  } else {
+    AddressClass::Public
+  }
+}
+
+fn ipv4_mapped(ip: &Ipv6Addr) -> Option<Ipv4Addr> {
+  match ip.octets() {
+    [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, a, b, c, d] => Some(Ipv4Addr::new(a, b, c, d)),
+    _ => None,
+  }
+}
+
+/// Overlay addresses such as onions are always considered public.
+pub fn classify(address: &Address) -> AddressClass {
+  match address {
+    Address::IPV4(ip, _) => classify_ipv4(Ipv4Addr::from(*ip)),
+    Address::IPV6(ip, _) => classify_ipv6(Ipv6Addr::from(*ip)),
+    #[cfg(any(feature = "tor", feature = "i2p"))]
+    _ => AddressClass::Public,
+  }
+}
+
+/// Decides which peer addresses get stored, public addresses are always
+/// accepted, other classes only if they have been explicitly allowed.
+pub struct AddressFilter {
+  allowed: Vec<AddressClass>,
+}
+
+impl AddressFilter {
+  pub fn new(allowed: Vec<AddressClass>) -> AddressFilter {
+    AddressFilter { allowed }
+  }
+
+  pub fn accepts(&self, address: &Address) -> bool {
+    match classify(address) {
+      AddressClass::Public => true,
+      AddressClass::Reserved => false,
+      class => self.allowed.contains(&class),
+    }
+  }
+}
@@ -0,0 +1,55 @@
+use std::collections::HashSet;
+
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use zeronet_peerdb::Hash;
+
+use crate::denylist;
+
+/// Characters of the base58 encoding that site addresses use.
+const BASE58_ALPHABET: &str = "123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+fn is_site_address(entry: &str) -> bool {
+  entry.starts_with('1')
+    && (26..=35).contains(&entry.len())
+    && entry.chars().all(|c| BASE58_ALPHABET.contains(c))
+}
+
+/// Parses an allowlist entry into the hashes it permits: a ZeroNet site
+/// address, which ZeroNet clients announce as the SHA-256 of the address
+/// and BitTorrent clients as its SHA-1, or a hash in hex or base64.
+/// Hashes must have the length of a site or a BitTorrent info hash, so a
+/// mistyped site address is not taken for base64.
+pub fn parse_entry(entry: &str) -> Option<Vec<Hash>> {
+  if is_site_address(entry) {
+    return Some(vec![
+      Hash(Sha256::digest(entry.as_bytes()).to_vec()),
+      Hash(Sha1::digest(entry.as_bytes()).to_vec()),
+    ]);
+  }
+  let hash = denylist::parse_hash(entry)?;
+  Some(vec![hash])
+}
+
+/// Hashes stored and answered by a private tracker. An empty allowlist
+/// permits every hash, as on a public tracker.
+#[derive(Debug, Default)]
+pub struct Allowlist {
+  hashes: HashSet<Hash>,
+}
+
+impl Allowlist {
+  pub fn new(hashes: Vec<Hash>) -> Allowlist {
+    Allowlist {
+      hashes: hashes.into_iter().collect(),
+    }
+  }
+
+  pub fn is_private(&self) -> bool {
+    !self.hashes.is_empty()
+  }
+
+  pub fn permits(&self, hash: &Hash) -> bool {
+    !self.is_private() || self.hashes.contains(hash)
+  }
+}
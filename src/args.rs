@@ -1,31 +1,107 @@
-#[cfg(feature = "sql")]
+use std::ffi::OsString;
+use std::net::IpAddr;
 use std::path::PathBuf;
 
 use clap::{command, Arg};
+use zeronet_peerdb::Hash;
+
+use crate::address_filter::AddressClass;
+use crate::allowlist;
 
 pub struct Args {
   pub port:     u16,
   pub address:  String,
   pub interval: u16,
   pub timeout:  u16,
+  pub peer_id:  Option<String>,
+
+  pub max_connections:        usize,
+  pub max_connections_per_ip: usize,
+  pub request_rate:           u16,
+  pub request_burst:          u16,
+  pub idle_timeout:           u16,
+  pub read_timeout:           u16,
+  pub write_timeout:          u16,
+  pub shutdown_timeout:       u16,
+  pub max_peers:              usize,
+  pub max_announce_hashes:    usize,
+  pub max_peer_hashes:        usize,
+  pub max_hash_peers:         usize,
+  pub max_total_peers:        usize,
+  pub max_total_hashes:       usize,
+  pub require_handshake:      bool,
+
+  pub allowed_addresses: Vec<AddressClass>,
+
+  pub verify_reachability: bool,
+  pub reachability_cache:  u16,
+
+  pub udp_port: u16,
+
+  pub trusted_proxies: Vec<IpAddr>,
+
+  pub denylist:  Option<PathBuf>,
+  pub allowlist: Vec<Hash>,
 
   #[cfg(feature = "server")]
   pub rocket_port: u16,
 
   #[cfg(feature = "sql")]
   pub database_file: Option<PathBuf>,
+
+  #[cfg(feature = "tls")]
+  pub tls_cert: Option<PathBuf>,
+  #[cfg(feature = "tls")]
+  pub tls_key:  Option<PathBuf>,
 }
 
 fn is_u16(v: &str) -> Result<(), String> {
   let res: Result<u16, _> = v.parse();
-  if res.is_ok() {
-    return Ok(());
-  } else {
-    return Err(format!("'{}' cannot be parsed to u16.", v));
+  match res {
+    Ok(_) => Ok(()),
+    Err(_) => Err(format!("'{}' cannot be parsed to u16.", v)),
+  }
+}
+
+fn is_peer_id(v: &str) -> Result<(), String> {
+  match v.len() {
+    1..=20 => Ok(()),
+    _ => Err(format!("'{}' is not between 1 and 20 bytes long.", v)),
+  }
+}
+
+fn is_ip(v: &str) -> Result<(), String> {
+  let res: Result<IpAddr, _> = v.parse();
+  match res {
+    Ok(_) => Ok(()),
+    Err(_) => Err(format!("'{}' cannot be parsed to an IP address.", v)),
+  }
+}
+
+fn is_allowlist_entry(v: &str) -> Result<(), String> {
+  match allowlist::parse_entry(v) {
+    Some(_) => Ok(()),
+    None => Err(format!("'{}' is neither a site address nor a hash.", v)),
+  }
+}
+
+fn is_usize(v: &str) -> Result<(), String> {
+  let res: Result<usize, _> = v.parse();
+  match res {
+    Ok(_) => Ok(()),
+    Err(_) => Err(format!("'{}' cannot be parsed to usize.", v)),
   }
 }
 
 pub fn get_arguments() -> Args {
+  get_arguments_from(std::env::args_os())
+}
+
+pub fn get_arguments_from<I, T>(itr: I) -> Args
+where
+  I: IntoIterator<Item = T>,
+  T: Into<OsString> + Clone,
+{
   let mut app = command!();
   app = app
     .arg(
@@ -63,6 +139,192 @@ pub fn get_arguments() -> Args {
         .env("PEER_TIMEOUT")
         .validator(is_u16)
         .default_value("50"),
+    )
+    .arg(
+      Arg::new("peer_id")
+        .long("peer_id")
+        .help("Peer ID the tracker uses in handshakes, generated on start when not given.")
+        .env("PEER_ID")
+        .validator(is_peer_id)
+        .allow_hyphen_values(true)
+        .takes_value(true),
+    )
+    .arg(
+      Arg::new("max_connections")
+        .long("max_connections")
+        .help("Maximum number of peer connections handled concurrently.")
+        .env("MAX_CONNECTIONS")
+        .validator(is_usize)
+        .default_value("1024"),
+    )
+    .arg(
+      Arg::new("max_connections_per_ip")
+        .long("max_connections_per_ip")
+        .help("Maximum number of concurrent peer connections from a single IP, 0 for no limit.")
+        .env("MAX_CONNECTIONS_PER_IP")
+        .validator(is_usize)
+        .default_value("0"),
+    )
+    .arg(
+      Arg::new("request_rate")
+        .long("request_rate")
+        .help("Number of requests per second allowed from a single IP, 0 for no limit.")
+        .env("REQUEST_RATE")
+        .validator(is_u16)
+        .default_value("0"),
+    )
+    .arg(
+      Arg::new("request_burst")
+        .long("request_burst")
+        .help("Number of requests a single IP can make in a burst above the request rate.")
+        .env("REQUEST_BURST")
+        .validator(is_u16)
+        .default_value("50"),
+    )
+    .arg(
+      Arg::new("idle_timeout")
+        .long("idle_timeout")
+        .help("Seconds to wait for the next request on a connection, 0 to wait forever.")
+        .env("IDLE_TIMEOUT")
+        .validator(is_u16)
+        .default_value("300"),
+    )
+    .arg(
+      Arg::new("read_timeout")
+        .long("read_timeout")
+        .help("Seconds a peer may take to finish sending a request it started, 0 to wait forever.")
+        .env("READ_TIMEOUT")
+        .validator(is_u16)
+        .default_value("60"),
+    )
+    .arg(
+      Arg::new("write_timeout")
+        .long("write_timeout")
+        .help("Seconds a peer may take to receive a response, 0 to wait forever.")
+        .env("WRITE_TIMEOUT")
+        .validator(is_u16)
+        .default_value("30"),
+    )
+    .arg(
+      Arg::new("shutdown_timeout")
+        .long("shutdown_timeout")
+        .help("Seconds to wait for open connections to finish when shutting down.")
+        .env("SHUTDOWN_TIMEOUT")
+        .validator(is_u16)
+        .default_value("10"),
+    )
+    .arg(
+      Arg::new("max_peers")
+        .long("max_peers")
+        .help("Maximum number of peers of each type returned per hash, regardless of need_num.")
+        .env("MAX_PEERS")
+        .validator(is_usize)
+        .default_value("30"),
+    )
+    .arg(
+      Arg::new("max_announce_hashes")
+        .long("max_announce_hashes")
+        .help("Maximum number of hashes in a single announce, 0 for no limit.")
+        .env("MAX_ANNOUNCE_HASHES")
+        .validator(is_usize)
+        .default_value("0"),
+    )
+    .arg(
+      Arg::new("max_peer_hashes")
+        .long("max_peer_hashes")
+        .help("Maximum number of distinct hashes stored for a peer, 0 for no limit.")
+        .env("MAX_PEER_HASHES")
+        .validator(is_usize)
+        .default_value("0"),
+    )
+    .arg(
+      Arg::new("max_hash_peers")
+        .long("max_hash_peers")
+        .help("Maximum number of peers stored per hash, evicting the oldest, 0 for no limit.")
+        .env("MAX_HASH_PEERS")
+        .validator(is_usize)
+        .default_value("0"),
+    )
+    .arg(
+      Arg::new("max_total_peers")
+        .long("max_total_peers")
+        .help("Maximum number of peers stored, evicting the oldest, 0 for no limit.")
+        .env("MAX_TOTAL_PEERS")
+        .validator(is_usize)
+        .default_value("0"),
+    )
+    .arg(
+      Arg::new("max_total_hashes")
+        .long("max_total_hashes")
+        .help("Maximum number of hashes stored, evicting the oldest peers, 0 for no limit.")
+        .env("MAX_TOTAL_HASHES")
+        .validator(is_usize)
+        .default_value("0"),
+    )
+    .arg(
+      Arg::new("require_handshake")
+        .long("require_handshake")
+        .help("Reject announces on connections that have not sent a handshake.")
+        .env("REQUIRE_HANDSHAKE"),
+    )
+    .arg(
+      Arg::new("allow_addresses")
+        .long("allow_addresses")
+        .help("Non-public address ranges to accept peers from, e.g. for LAN deployments.")
+        .env("ALLOW_ADDRESSES")
+        .takes_value(true)
+        .multiple_values(true)
+        .use_value_delimiter(true)
+        .possible_values(AddressClass::ALLOWABLE),
+    )
+    .arg(
+      Arg::new("verify_reachability")
+        .long("verify_reachability")
+        .help("Only list peers after connecting back to their fileserver port.")
+        .env("VERIFY_REACHABILITY"),
+    )
+    .arg(
+      Arg::new("reachability_cache")
+        .long("reachability_cache")
+        .help("Number of minutes to remember the result of a reachability check.")
+        .env("REACHABILITY_CACHE")
+        .validator(is_u16)
+        .default_value("30"),
+    )
+    .arg(
+      Arg::new("udp_port")
+        .long("udp_port")
+        .help("Port for the BitTorrent UDP tracker protocol, 0 disables it.")
+        .env("UDP_PORT")
+        .validator(is_u16)
+        .default_value("0"),
+    )
+    .arg(
+      Arg::new("trusted_proxies")
+        .long("trusted_proxies")
+        .help("IPs of proxies that send a PROXY protocol header in front of each connection.")
+        .env("TRUSTED_PROXIES")
+        .takes_value(true)
+        .multiple_values(true)
+        .use_value_delimiter(true)
+        .validator(is_ip),
+    )
+    .arg(
+      Arg::new("denylist")
+        .long("denylist")
+        .help("File of IP ranges and hashes to refuse, reloaded on change or SIGHUP.")
+        .env("DENYLIST")
+        .takes_value(true),
+    )
+    .arg(
+      Arg::new("allowlist")
+        .long("allowlist")
+        .help("Site addresses or hashes to track exclusively, making the tracker private.")
+        .env("ALLOWLIST")
+        .takes_value(true)
+        .multiple_values(true)
+        .use_value_delimiter(true)
+        .validator(is_allowlist_entry),
     );
 
   #[cfg(feature = "server")]
@@ -90,7 +352,28 @@ pub fn get_arguments() -> Args {
     );
   }
 
-  let matches = app.get_matches();
+  #[cfg(feature = "tls")]
+  {
+    app = app
+      .arg(
+        Arg::new("tls_cert")
+          .long("tls_cert")
+          .help("Path to a PEM certificate for encrypted connections, generated if not set.")
+          .env("TLS_CERT")
+          .requires("tls_key")
+          .takes_value(true),
+      )
+      .arg(
+        Arg::new("tls_key")
+          .long("tls_key")
+          .help("Path to the PEM private key belonging to the certificate.")
+          .env("TLS_KEY")
+          .requires("tls_cert")
+          .takes_value(true),
+      );
+  }
+
+  let matches = app.get_matches_from(itr);
   let args = Args {
     port:     matches.value_of("listener_port").unwrap().parse().unwrap(),
     address:  matches.value_of("address").unwrap().to_string(),
@@ -100,6 +383,68 @@ pub fn get_arguments() -> Args {
       .parse()
       .unwrap(),
     timeout:  matches.value_of("timeout").unwrap().parse().unwrap(),
+    peer_id:  matches.value_of("peer_id").map(|p| p.to_string()),
+
+    max_connections:        matches
+      .value_of("max_connections")
+      .unwrap()
+      .parse()
+      .unwrap(),
+    max_connections_per_ip: matches
+      .value_of("max_connections_per_ip")
+      .unwrap()
+      .parse()
+      .unwrap(),
+    request_rate:           matches.value_of("request_rate").unwrap().parse().unwrap(),
+    request_burst:          matches.value_of("request_burst").unwrap().parse().unwrap(),
+    idle_timeout:           matches.value_of("idle_timeout").unwrap().parse().unwrap(),
+    read_timeout:           matches.value_of("read_timeout").unwrap().parse().unwrap(),
+    write_timeout:          matches.value_of("write_timeout").unwrap().parse().unwrap(),
+    shutdown_timeout:       matches
+      .value_of("shutdown_timeout")
+      .unwrap()
+      .parse()
+      .unwrap(),
+    max_peers:              matches.value_of("max_peers").unwrap().parse().unwrap(),
+    max_announce_hashes:    matches
+      .value_of("max_announce_hashes")
+      .unwrap()
+      .parse()
+      .unwrap(),
+    max_peer_hashes:        matches.value_of("max_peer_hashes").unwrap().parse().unwrap(),
+    max_hash_peers:         matches.value_of("max_hash_peers").unwrap().parse().unwrap(),
+    max_total_peers:        matches.value_of("max_total_peers").unwrap().parse().unwrap(),
+    max_total_hashes:       matches
+      .value_of("max_total_hashes")
+      .unwrap()
+      .parse()
+      .unwrap(),
+    require_handshake:      matches.is_present("require_handshake"),
+
+    allowed_addresses: matches
+      .values_of("allow_addresses")
+      .map(|classes| classes.map(|class| class.parse().unwrap()).collect())
+      .unwrap_or_default(),
+
+    verify_reachability: matches.is_present("verify_reachability"),
+    reachability_cache:  matches
+      .value_of("reachability_cache")
+      .unwrap()
+      .parse()
+      .unwrap(),
+
+    udp_port: matches.value_of("udp_port").unwrap().parse().unwrap(),
+
+    trusted_proxies: matches
+      .values_of("trusted_proxies")
+      .map(|proxies| proxies.map(|proxy| proxy.parse().unwrap()).collect())
+      .unwrap_or_default(),
+
+    denylist:  matches.value_of("denylist").map(|p| p.parse().unwrap()),
+    allowlist: matches
+      .values_of("allowlist")
+      .map(|entries| entries.flat_map(|entry| allowlist::parse_entry(entry).unwrap()).collect())
+      .unwrap_or_default(),
 
     #[cfg(feature = "server")]
     rocket_port:                            matches
@@ -112,6 +457,15 @@ pub fn get_arguments() -> Args {
     database_file:                         matches
       .value_of("database_file")
       .map(|p| p.parse().unwrap()),
+
+    #[cfg(feature = "tls")]
+    tls_cert:                              matches
+      .value_of("tls_cert")
+      .map(|p| p.parse().unwrap()),
+    #[cfg(feature = "tls")]
+    tls_key:                               matches
+      .value_of("tls_key")
+      .map(|p| p.parse().unwrap()),
   };
 
   args
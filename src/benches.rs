@@ -0,0 +1,250 @@
+extern crate test;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::SystemTime;
+
+use test::Bencher;
+use zeronet_peerdb::{Hash, Peer};
+use zeronet_protocol::PeerAddr;
+
+use crate::peer_db::PeerStore;
+use crate::sharded_peer_db::ShardedPeerDB;
+#[cfg(feature = "sql")]
+use crate::sqlite_peer_db::SqlitePeerDB;
+#[cfg(feature = "sql")]
+use crate::tests::temp_database;
+
+const PEERS: u16 = 100;
+const HASHES: u8 = 16;
+/// Sites of a client announcing all of them at once
+const ANNOUNCED_HASHES: u16 = 1000;
+
+fn peer(i: u16) -> Peer {
+  Peer {
+    address:    PeerAddr::IPV4([10, 0, (i >> 8) as u8, i as u8], 15441),
+    date_added: SystemTime::now(),
+    last_seen:  SystemTime::now(),
+  }
+}
+
+/// Stores `PEERS` peers for each of `HASHES` hashes.
+fn filled<S: PeerStore>(peer_db: S) -> S {
+  let hashes: Vec<Hash> = (0..HASHES).map(|h| Hash(vec![h; 32])).collect();
+  for i in 0..PEERS {
+    peer_db.update_peer(&peer(i), &hashes).unwrap();
+  }
+  peer_db
+}
+
+/// Access to a peer database as connections use it.
+trait Tracker: Send + Sync + 'static {
+  fn lookup(&self, hash: &Hash) -> usize;
+  fn announce(&self, peer: &Peer, hashes: &[Hash]);
+}
+
+/// Relies on the locking within the peer database.
+struct Unlocked<S>(S);
+
+impl<S: PeerStore + 'static> Tracker for Unlocked<S> {
+  fn lookup(&self, hash: &Hash) -> usize {
+    self.0.get_peers_for_hash(hash).unwrap().len()
+  }
+
+  fn announce(&self, peer: &Peer, hashes: &[Hash]) {
+    self.0.update_peer(peer, hashes).unwrap();
+  }
+}
+
+/// Holds a single lock around the peer database, exclusively for
+/// announces, as connections did before the database locked internally.
+struct GlobalLock<S>(RwLock<S>);
+
+impl<S: PeerStore + 'static> Tracker for GlobalLock<S> {
+  fn lookup(&self, hash: &Hash) -> usize {
+    let peer_db = self.0.read().unwrap();
+    peer_db.get_peers_for_hash(hash).unwrap().len()
+  }
+
+  fn announce(&self, peer: &Peer, hashes: &[Hash]) {
+    self.0.write().unwrap().update_peer(peer, hashes).unwrap();
+  }
+}
+
+/// Keeps announcing other peers for `ANNOUNCED_HASHES` hashes that no
+/// lookup asks for on a separate thread, until dropped.
+struct Announcer {
+  stop:   Arc<AtomicBool>,
+  thread: Option<JoinHandle<()>>,
+}
+
+impl Announcer {
+  fn start<T: Tracker>(tracker: &Arc<T>) -> Announcer {
+    let stop = Arc::new(AtomicBool::new(false));
+    let thread = {
+      let (tracker, stop) = (tracker.clone(), stop.clone());
+      thread::spawn(move || {
+        let hashes: Vec<Hash> = (0..ANNOUNCED_HASHES)
+          .map(|h| Hash([&h.to_be_bytes()[..], &[HASHES; 30]].concat()))
+          .collect();
+        let mut i = 0;
+        while !stop.load(Ordering::Relaxed) {
+          tracker.announce(&peer(PEERS + i % PEERS), &hashes);
+          i += 1;
+        }
+      })
+    };
+    Announcer {
+      stop,
+      thread: Some(thread),
+    }
+  }
+}
+
+impl Drop for Announcer {
+  fn drop(&mut self) {
+    self.stop.store(true, Ordering::Relaxed);
+    self.thread.take().unwrap().join().unwrap();
+  }
+}
+
+/// Measures how long a lookup takes while another thread keeps storing
+/// announces, including any wait for the announces to get out of the way.
+fn bench_lookups_during_announces<T: Tracker>(b: &mut Bencher, tracker: T) {
+  let tracker = Arc::new(tracker);
+  let _announcer = Announcer::start(&tracker);
+  let hashes: Vec<Hash> = (0..HASHES).map(|h| Hash(vec![h; 32])).collect();
+  let mut hashes = hashes.iter().cycle();
+  b.iter(|| assert_eq!(tracker.lookup(hashes.next().unwrap()), PEERS as usize));
+}
+
+#[bench]
+fn bench_lookups_during_announces_global_lock(b: &mut Bencher) {
+  let tracker = GlobalLock(RwLock::new(filled(ShardedPeerDB::new())));
+  bench_lookups_during_announces(b, tracker);
+}
+
+#[bench]
+fn bench_lookups_during_announces_sharded(b: &mut Bencher) {
+  let tracker = Unlocked(filled(ShardedPeerDB::new()));
+  bench_lookups_during_announces(b, tracker);
+}
+
+/// Storing a large announce in SQLite takes milliseconds, which lookups
+/// have to wait for as long as the announce holds the lock.
+#[cfg(feature = "sql")]
+#[bench]
+fn bench_sqlite_lookups_during_announces_global_lock(b: &mut Bencher) {
+  let peer_db = SqlitePeerDB::open(&temp_database("bench_global_lock")).unwrap();
+  let tracker = GlobalLock(RwLock::new(filled(peer_db)));
+  bench_lookups_during_announces(b, tracker);
+}
+
+#[cfg(feature = "sql")]
+#[bench]
+fn bench_sqlite_lookups_during_announces_pooled(b: &mut Bencher) {
+  let peer_db = SqlitePeerDB::open(&temp_database("bench_pooled")).unwrap();
+  let tracker = Unlocked(filled(peer_db));
+  bench_lookups_during_announces(b, tracker);
+}
+
+/// An announce for many sites, some of which are listed twice.
+fn announced_hashes() -> Vec<Hash> {
+  (0..200).map(|h| Hash(vec![(h % 150) as u8; 32])).collect()
+}
+
+fn bench_announce_lookups_per_hash<S: PeerStore>(b: &mut Bencher, peer_db: S) {
+  let peer_db = filled(peer_db);
+  let hashes = announced_hashes();
+  b.iter(|| {
+    hashes
+      .iter()
+      .map(|hash| peer_db.get_peers_for_hash(hash).unwrap().len())
+      .sum::<usize>()
+  });
+}
+
+fn bench_announce_lookups_batched<S: PeerStore>(b: &mut Bencher, peer_db: S) {
+  let peer_db = filled(peer_db);
+  let hashes = announced_hashes();
+  b.iter(|| peer_db.get_peers_for_hashes(&hashes).unwrap().len());
+}
+
+#[bench]
+fn bench_memory_announce_lookups_per_hash(b: &mut Bencher) {
+  bench_announce_lookups_per_hash(b, ShardedPeerDB::new());
+}
+
+#[bench]
+fn bench_memory_announce_lookups_batched(b: &mut Bencher) {
+  bench_announce_lookups_batched(b, ShardedPeerDB::new());
+}
+
+#[cfg(feature = "sql")]
+#[bench]
+fn bench_sqlite_announce_lookups_per_hash(b: &mut Bencher) {
+  let peer_db = SqlitePeerDB::open(&temp_database("bench_lookups_per_hash")).unwrap();
+  bench_announce_lookups_per_hash(b, peer_db);
+}
+
+#[cfg(feature = "sql")]
+#[bench]
+fn bench_sqlite_announce_lookups_batched(b: &mut Bencher) {
+  let peer_db = SqlitePeerDB::open(&temp_database("bench_lookups_batched")).unwrap();
+  bench_announce_lookups_batched(b, peer_db);
+}
+
+/// The onions of an announce, each with the hashes of its sites.
+fn onion_updates() -> Vec<(Peer, Vec<Hash>)> {
+  (0..50u8)
+    .map(|i| {
+      let peer = Peer {
+        address:    PeerAddr::IPV4([10, 1, 0, i], 15441),
+        date_added: SystemTime::now(),
+        last_seen:  SystemTime::now(),
+      };
+      (peer, vec![Hash(vec![i; 32]), Hash(vec![i, 1])])
+    })
+    .collect()
+}
+
+fn bench_onion_updates_per_peer<S: PeerStore>(b: &mut Bencher, peer_db: S) {
+  let peer_db = filled(peer_db);
+  let updates = onion_updates();
+  b.iter(|| {
+    for (peer, hashes) in updates.iter() {
+      peer_db.update_peer(peer, hashes).unwrap();
+    }
+  });
+}
+
+fn bench_onion_updates_batched<S: PeerStore>(b: &mut Bencher, peer_db: S) {
+  let peer_db = filled(peer_db);
+  let updates = onion_updates();
+  b.iter(|| peer_db.update_peers(&updates).unwrap());
+}
+
+#[bench]
+fn bench_memory_onion_updates_per_peer(b: &mut Bencher) {
+  bench_onion_updates_per_peer(b, ShardedPeerDB::new());
+}
+
+#[bench]
+fn bench_memory_onion_updates_batched(b: &mut Bencher) {
+  bench_onion_updates_batched(b, ShardedPeerDB::new());
+}
+
+#[cfg(feature = "sql")]
+#[bench]
+fn bench_sqlite_onion_updates_per_peer(b: &mut Bencher) {
+  let peer_db = SqlitePeerDB::open(&temp_database("bench_updates_per_peer")).unwrap();
+  bench_onion_updates_per_peer(b, peer_db);
+}
+
+#[cfg(feature = "sql")]
+#[bench]
+fn bench_sqlite_onion_updates_batched(b: &mut Bencher) {
+  let peer_db = SqlitePeerDB::open(&temp_database("bench_updates_batched")).unwrap();
+  bench_onion_updates_batched(b, peer_db);
+}
@@ -0,0 +1,200 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+use log::*;
+use rand::seq::SliceRandom;
+use rand::thread_rng;
+use zeronet_peerdb::{Hash, Peer};
+use zeronet_protocol::PeerAddr as Address;
+
+use crate::address_filter::AddressFilter;
+use crate::allowlist::Allowlist;
+use crate::args::Args;
+use crate::denylist::{self, Denylist};
+#[cfg(feature = "metrics")]
+use crate::metrics;
+use crate::peer_db::Error as PeerDBError;
+use crate::shared_state::{self, SharedState};
+
+/// Length of BitTorrent info hashes and peer ids.
+pub const ID_LENGTH: usize = 20;
+/// Number of peers returned when the client does not ask for a number.
+const DEFAULT_NUMWANT: usize = 50;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+  Started,
+  Completed,
+  Stopped,
+}
+
+/// An announce of the HTTP or the UDP tracker protocol.
+#[derive(Debug)]
+pub struct AnnounceRequest {
+  pub info_hash: Hash,
+  pub port:      u16,
+  pub event:     Option<Event>,
+  pub numwant:   Option<usize>,
+}
+
+pub struct TrackerConfig {
+  pub max_peers:      usize,
+  /// Seconds clients should wait between announces.
+  pub interval:       u32,
+  pub address_filter: AddressFilter,
+  /// Shared with the ZeroNet listener, which reloads it
+  pub denylist:       Arc<RwLock<Denylist>>,
+  pub allowlist:      Allowlist,
+}
+
+impl TrackerConfig {
+  pub fn new(args: &Args, denylist: Arc<RwLock<Denylist>>) -> TrackerConfig {
+    TrackerConfig {
+      max_peers:      args.max_peers,
+      // Announce twice within the peer timeout, so a single missed
+      // announce does not get a peer removed.
+      interval:       30 * args.timeout as u32,
+      address_filter: AddressFilter::new(args.allowed_addresses.clone()),
+      denylist,
+      allowlist:      Allowlist::new(args.allowlist.clone()),
+    }
+  }
+
+  /// Whether requests from `ip` are refused, counting them if so.
+  fn denies_ip(&self, ip: IpAddr) -> bool {
+    let denied = self.denylist.read().unwrap().denies_ip(ip);
+    if denied {
+      info!("Refusing BitTorrent request from denied address {}", ip);
+      #[cfg(feature = "metrics")]
+      metrics::DENIED_CONNECTIONS.inc();
+    }
+    denied
+  }
+
+  /// Whether `hash` is neither stored nor answered, counting it if it is
+  /// on the denylist.
+  fn ignores_hash(&self, hash: &Hash) -> bool {
+    if self.denylist.read().unwrap().denies_hash(hash) {
+      #[cfg(feature = "metrics")]
+      metrics::DENIED_HASHES.inc();
+      return true;
+    }
+    !self.allowlist.permits(hash)
+  }
+}
+
+impl From<&Args> for TrackerConfig {
+  fn from(args: &Args) -> TrackerConfig {
+    TrackerConfig::new(args, denylist::shared(args.denylist.as_deref()))
+  }
+}
+
+/// The peers of a hash, returned for an announce.
+pub struct Swarm {
+  /// Number of peers announcing the hash, including the announcing one.
+  pub size:  usize,
+  pub peers: Vec<Peer>,
+}
+
+/// Stores the announcing peer and picks a random sample of IP peers of
+/// the hash for it. Denied addresses, denied hashes and hashes a private
+/// tracker does not track get an empty swarm.
+///
+/// A stopped event does not remove the peer: ZeroNet clients share their
+/// address between all of their sites, so it is only not refreshed and
+/// expires with the peer timeout unless announced again.
+pub fn announce(
+  shared_state: &RwLock<SharedState>,
+  config: &TrackerConfig,
+  ip: IpAddr,
+  request: &AnnounceRequest,
+) -> Result<Swarm, PeerDBError> {
+  if config.denies_ip(ip) || config.ignores_hash(&request.info_hash) {
+    return Ok(Swarm {
+      size:  0,
+      peers: Vec::new(),
+    });
+  }
+
+  let address = Address::from(SocketAddr::new(ip.to_canonical(), request.port));
+  if request.event != Some(Event::Stopped) && config.address_filter.accepts(&address) {
+    let shared_state = shared_state::read(shared_state);
+    let date_added = match shared_state.peer_db.get_peer(&address)? {
+      Some(peer) => peer.date_added,
+      None => SystemTime::now(),
+    };
+    let peer = Peer {
+      address: address.clone(),
+      last_seen: SystemTime::now(),
+      date_added,
+    };
+    let hashes = vec![request.info_hash.clone()];
+    shared_state.peer_db.update_peer(&peer, &hashes)?;
+    shared_state.enforce_budget(&hashes)?;
+  }
+
+  let mut peers = shared_state::read(shared_state)
+    .peer_db
+    .get_peers_for_hash(&request.info_hash)?;
+  let size = peers.len();
+  peers.retain(|peer| {
+    peer.address != address && matches!(peer.address, Address::IPV4(..) | Address::IPV6(..))
+  });
+  let limit = request
+    .numwant
+    .unwrap_or(DEFAULT_NUMWANT)
+    .min(config.max_peers);
+  peers.shuffle(&mut thread_rng());
+  peers.truncate(limit);
+
+  Ok(Swarm { size, peers })
+}
+
+/// Counts the peers of each hash. Denied addresses, denied hashes and
+/// hashes a private tracker does not track get a count of zero.
+pub fn scrape(
+  shared_state: &RwLock<SharedState>,
+  config: &TrackerConfig,
+  ip: IpAddr,
+  hashes: &[Hash],
+) -> Result<Vec<usize>, PeerDBError> {
+  if config.denies_ip(ip) {
+    return Ok(vec![0; hashes.len()]);
+  }
+  let ignored: Vec<bool> = hashes.iter().map(|hash| config.ignores_hash(hash)).collect();
+  let shared_state = shared_state::read(shared_state);
+  let peers = shared_state.peer_db.get_peers_for_hashes(hashes)?;
+  Ok(
+    peers
+      .iter()
+      .zip(ignored)
+      .map(|(peers, ignored)| if ignored { 0 } else { peers.len() })
+      .collect(),
+  )
+}
+
+pub fn peer_ip(address: &Address) -> Option<(IpAddr, u16)> {
+  match address {
+    Address::IPV4(ip, port) => Some((IpAddr::from(*ip), *port)),
+    Address::IPV6(ip, port) => Some((IpAddr::from(*ip), *port)),
+    #[allow(unreachable_patterns)]
+    _ => None,
+  }
+}
+
+/// Packs the IPv4 or IPv6 peers as addresses followed by ports in network
+/// byte order. ZeroNet packs ports in little endian, so `Address::pack`
+/// cannot be used here.
+pub fn pack_peers(peers: &[Peer], ipv6: bool) -> Vec<u8> {
+  let mut packed = Vec::new();
+  for (ip, port) in peers.iter().filter_map(|peer| peer_ip(&peer.address)) {
+    match ip {
+      IpAddr::V4(ip) if !ipv6 => packed.extend(ip.octets()),
+      IpAddr::V6(ip) if ipv6 => packed.extend(ip.octets()),
+      _ => continue,
+    }
+    packed.extend(port.to_be_bytes());
+  }
+  packed
+}
@@ -0,0 +1,82 @@
+use log::*;
+use zeronet_peerdb::Hash;
+
+use crate::args::Args;
+#[cfg(feature = "metrics")]
+use crate::metrics;
+use crate::peer_db::{Error, PeerStore};
+
+/// Part of a total budget that is freed when it is exceeded, so eviction
+/// does not have to run again on every following announce.
+const HEADROOM_DIVISOR: usize = 10;
+
+/// Limits on the size of the peer database. Peers seen least recently are
+/// evicted first when a limit is exceeded.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Budget {
+  /// Peers stored per hash
+  pub max_hash_peers: Option<usize>,
+  /// Peers stored in total
+  pub max_peers:      Option<usize>,
+  /// Hashes stored in total
+  pub max_hashes:     Option<usize>,
+}
+
+fn limit(max: usize) -> Option<usize> {
+  match max {
+    0 => None,
+    max => Some(max),
+  }
+}
+
+fn with_headroom(max: Option<usize>) -> usize {
+  max.map_or(usize::MAX, |max| max - max / HEADROOM_DIVISOR)
+}
+
+impl From<&Args> for Budget {
+  fn from(args: &Args) -> Budget {
+    Budget {
+      max_hash_peers: limit(args.max_hash_peers),
+      max_peers:      limit(args.max_total_peers),
+      max_hashes:     limit(args.max_total_hashes),
+    }
+  }
+}
+
+impl Budget {
+  /// Evicts peers until the just announced `hashes` and the database as a
+  /// whole are within the budget. Returns the number of evicted peers.
+  pub fn enforce(
+    &self,
+    peer_db: &dyn PeerStore,
+    hashes: &[Hash],
+  ) -> Result<usize, Error> {
+    let mut evicted = 0;
+    if let Some(max_hash_peers) = self.max_hash_peers {
+      for hash in hashes {
+        evicted += peer_db.evict_from_hash(hash, max_hash_peers)?;
+      }
+    }
+
+    let over_peers = match self.max_peers {
+      Some(max_peers) => peer_db.get_peer_count()? > max_peers,
+      None => false,
+    };
+    let over_hashes = match self.max_hashes {
+      Some(max_hashes) => peer_db.get_hash_count()? > max_hashes,
+      None => false,
+    };
+    if over_peers || over_hashes {
+      let max_peers = with_headroom(self.max_peers);
+      let max_hashes = with_headroom(self.max_hashes);
+      evicted += peer_db.evict_oldest(max_peers, max_hashes)?;
+    }
+
+    if evicted > 0 {
+      info!("Evicted {} peers to stay within the budget", evicted);
+      #[cfg(feature = "metrics")]
+      metrics::EVICTED_PEERS.inc_by(evicted as u64);
+    }
+    Ok(evicted)
+  }
+}
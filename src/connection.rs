@@ -0,0 +1,282 @@
+use std::io;
+
+use rmp::Marker;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use zeronet_protocol::error::Error;
+use zeronet_protocol::message::{Request, Response, ZeroMessage};
+
+#[cfg(feature = "tls")]
+use crate::tls::{TlsContext, TlsStream};
+
+/// Size of the read buffer of a connection, grown for larger messages.
+const INITIAL_BUFFER_SIZE: usize = 1024;
+/// Largest message a peer may send, an announce of several thousand hashes
+/// and onions takes well under a megabyte.
+pub const MAX_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+
+enum Stream {
+  Plain(TcpStream),
+  #[cfg(feature = "tls")]
+  Tls(TlsStream),
+  /// Left behind by a failed switch to TLS or a closed connection
+  Closed,
+}
+
+impl Stream {
+  async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    match self {
+      Stream::Plain(stream) => stream.read(buf).await,
+      #[cfg(feature = "tls")]
+      Stream::Tls(stream) => stream.read(buf).await,
+      Stream::Closed => Ok(0),
+    }
+  }
+
+  async fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+    match self {
+      Stream::Plain(stream) => stream.write_all(buf).await,
+      #[cfg(feature = "tls")]
+      Stream::Tls(stream) => stream.write_all(buf).await,
+      Stream::Closed => Err(io::ErrorKind::NotConnected.into()),
+    }
+  }
+}
+
+/// A ZeroNet protocol connection on the tokio runtime.
+///
+/// `ZeroConnection` of zeronet_protocol starts an OS thread for every read
+/// and write, which ties up a thread for each idle peer. This reads and
+/// writes the MessagePack framing directly on the socket instead, so
+/// waiting peers only cost a task.
+pub struct Connection {
+  stream: Stream,
+  /// Received bytes of the next messages, `buffer[..filled]` is valid
+  buffer: Vec<u8>,
+  filled: usize,
+  /// How far the next message was scanned, so each read only scans the
+  /// bytes that arrived with it
+  scan:   MessageScan,
+}
+
+impl Connection {
+  pub fn new(stream: TcpStream) -> Connection {
+    Connection {
+      stream: Stream::Plain(stream),
+      buffer: vec![0; INITIAL_BUFFER_SIZE],
+      filled: 0,
+      scan:   MessageScan::default(),
+    }
+  }
+
+  /// Closes the connection, the next receive fails.
+  pub fn close(&mut self) {
+    self.stream = Stream::Closed;
+    self.filled = 0;
+    self.scan = MessageScan::default();
+  }
+
+  #[cfg(feature = "tls")]
+  pub fn is_plain(&self) -> bool {
+    matches!(self.stream, Stream::Plain(_))
+  }
+
+  /// Performs the server side of the TLS handshake, after which all
+  /// messages are encrypted. The connection is closed if it fails.
+  #[cfg(feature = "tls")]
+  pub async fn start_tls(&mut self, tls: &TlsContext) -> Result<(), String> {
+    let stream = std::mem::replace(&mut self.stream, Stream::Closed);
+    let stream = match stream {
+      // Anything the peer sent before its TLS handshake would be lost
+      Stream::Plain(stream) if self.filled == 0 => stream,
+      _ => return Err("Connection cannot switch to TLS".to_string()),
+    };
+    self.stream = Stream::Tls(tls.accept(stream).await?);
+    Ok(())
+  }
+
+  /// Waits until the peer starts sending its next message. Returns right
+  /// away if part of it was already received.
+  pub async fn wait_for_data(&mut self) -> Result<(), Error> {
+    match self.filled {
+      0 => self.fill().await,
+      _ => Ok(()),
+    }
+  }
+
+  /// Reads the next request.
+  pub async fn recv(&mut self) -> Result<Request, Error> {
+    match self.recv_message().await? {
+      ZeroMessage::Request(req) => Ok(req),
+      ZeroMessage::Response(_) => Err(Error::UnexpectedResponse),
+    }
+  }
+
+  pub async fn respond<T: DeserializeOwned + Serialize>(
+    &mut self,
+    to: usize,
+    body: T,
+  ) -> Result<(), Error> {
+    self.send(&ZeroMessage::response(to, body)).await
+  }
+
+  /// Sends a request and waits for its response, skipping anything else
+  /// the peer sends in the meantime.
+  pub async fn request<T: DeserializeOwned + Serialize>(
+    &mut self,
+    cmd: &str,
+    req_id: usize,
+    body: T,
+  ) -> Result<Response, Error> {
+    self.send(&ZeroMessage::request(cmd, req_id, body)).await?;
+    loop {
+      if let ZeroMessage::Response(res) = self.recv_message().await? {
+        if res.to == req_id {
+          return Ok(res);
+        }
+      }
+    }
+  }
+
+  async fn send(&mut self, message: &ZeroMessage) -> Result<(), Error> {
+    let bytes = rmp_serde::to_vec_named(message)?;
+    self.stream.write_all(&bytes).await?;
+    Ok(())
+  }
+
+  async fn recv_message(&mut self) -> Result<ZeroMessage, Error> {
+    loop {
+      if let Some(length) = self.scan.advance(&self.buffer[..self.filled])? {
+        let message = rmp_serde::from_slice(&self.buffer[..length]);
+        self.scan = MessageScan::default();
+        self.consume(length);
+        return Ok(message?);
+      }
+      self.fill().await?;
+    }
+  }
+
+  /// Reads whatever the peer sent next into the buffer.
+  async fn fill(&mut self) -> Result<(), Error> {
+    if self.filled == self.buffer.len() {
+      if self.buffer.len() >= MAX_MESSAGE_SIZE {
+        return Err(Error::text("Message too large"));
+      }
+      self.buffer.resize(self.buffer.len() * 2, 0);
+    }
+    match self.stream.read(&mut self.buffer[self.filled..]).await? {
+      0 => Err(Error::ConnectionClosed),
+      read => {
+        self.filled += read;
+        Ok(())
+      }
+    }
+  }
+
+  /// Drops a handled message from the buffer, shrinking it back after a
+  /// large message.
+  fn consume(&mut self, length: usize) {
+    self.buffer.copy_within(length..self.filled, 0);
+    self.filled -= length;
+    if self.buffer.len() > INITIAL_BUFFER_SIZE && self.filled <= INITIAL_BUFFER_SIZE {
+      self.buffer.truncate(INITIAL_BUFFER_SIZE);
+      self.buffer.shrink_to_fit();
+    }
+  }
+}
+
+/// Reads a big endian length of `size` bytes at `pos`.
+fn read_length(buf: &[u8], pos: usize, size: usize) -> Option<u64> {
+  let bytes = buf.get(pos..pos + size)?;
+  Some(bytes.iter().fold(0, |length, byte| length << 8 | *byte as u64))
+}
+
+/// Finds the length of the MessagePack value at the start of a buffer
+/// without decoding it, so declared lengths are never allocated before the
+/// data has arrived.
+///
+/// Scanning stops after the last complete value and picks up there once more
+/// of the message was received, which keeps a message that trickles in from
+/// being scanned again from the start on every read.
+#[derive(Clone, Copy, Debug)]
+pub struct MessageScan {
+  /// End of the values scanned so far
+  pos:    usize,
+  /// Values still to be skipped, including the elements of open containers
+  values: u64,
+}
+
+impl Default for MessageScan {
+  fn default() -> MessageScan {
+    MessageScan { pos: 0, values: 1 }
+  }
+}
+
+impl MessageScan {
+  /// Continues scanning `buf`, which has to start with everything scanned
+  /// before. Returns `None` if the value is not complete yet.
+  pub fn advance(&mut self, buf: &[u8]) -> Result<Option<usize>, Error> {
+    while self.values > 0 {
+      let mut pos = self.pos;
+      let marker = match buf.get(pos) {
+        Some(byte) => Marker::from_u8(*byte),
+        None => return Ok(None),
+      };
+      pos += 1;
+      // Bytes of the length field, and the bytes or values it counts
+      let (length_size, data, elements) = match marker {
+        Marker::FixPos(_) | Marker::FixNeg(_) | Marker::Null | Marker::True | Marker::False => {
+          (0, 0, 0)
+        }
+        Marker::U8 | Marker::I8 => (0, 1, 0),
+        Marker::U16 | Marker::I16 => (0, 2, 0),
+        Marker::U32 | Marker::I32 | Marker::F32 => (0, 4, 0),
+        Marker::U64 | Marker::I64 | Marker::F64 => (0, 8, 0),
+        Marker::FixStr(length) => (0, length as u64, 0),
+        Marker::Str8 | Marker::Bin8 => (1, 0, 0),
+        Marker::Str16 | Marker::Bin16 => (2, 0, 0),
+        Marker::Str32 | Marker::Bin32 => (4, 0, 0),
+        Marker::FixArray(length) => (0, 0, length as u64),
+        Marker::Array16 => (2, 0, 0),
+        Marker::Array32 => (4, 0, 0),
+        Marker::FixMap(length) => (0, 0, 2 * length as u64),
+        Marker::Map16 => (2, 0, 0),
+        Marker::Map32 => (4, 0, 0),
+        // Extensions carry a type byte before their data
+        Marker::FixExt1 => (0, 2, 0),
+        Marker::FixExt2 => (0, 3, 0),
+        Marker::FixExt4 => (0, 5, 0),
+        Marker::FixExt8 => (0, 9, 0),
+        Marker::FixExt16 => (0, 17, 0),
+        Marker::Ext8 => (1, 1, 0),
+        Marker::Ext16 => (2, 1, 0),
+        Marker::Ext32 => (4, 1, 0),
+        Marker::Reserved => return Err(Error::text("Invalid MessagePack marker")),
+      };
+
+      let (data, elements) = match length_size {
+        0 => (data, elements),
+        _ => {
+          let length = match read_length(buf, pos, length_size) {
+            Some(length) => length,
+            None => return Ok(None),
+          };
+          pos += length_size;
+          match marker {
+            Marker::Array16 | Marker::Array32 => (0, length),
+            Marker::Map16 | Marker::Map32 => (0, 2 * length),
+            _ => (data + length, 0),
+          }
+        }
+      };
+      if data > (buf.len() - pos) as u64 {
+        return Ok(None);
+      }
+      self.pos = pos + data as usize;
+      self.values = self.values - 1 + elements;
+    }
+    Ok(Some(self.pos))
+  }
+}
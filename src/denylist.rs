@@ -0,0 +1,223 @@
+use std::collections::HashSet;
+use std::future::pending;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use log::*;
+use tokio::time::sleep;
+use zeronet_peerdb::Hash;
+
+use crate::bittorrent::ID_LENGTH;
+use crate::shutdown::Shutdown;
+
+/// How often the denylist file is checked for changes.
+pub const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// An IP address range in CIDR notation.
+#[derive(Debug, PartialEq, Eq)]
+struct IpRange {
+  network: IpAddr,
+  prefix:  u32,
+}
+
+impl IpRange {
+  fn parse(entry: &str) -> Option<IpRange> {
+    let (network, prefix) = match entry.split_once('/') {
+      Some((network, prefix)) => (network.parse().ok()?, Some(prefix.parse().ok()?)),
+      None => (entry.parse().ok()?, None),
+    };
+    let bits = match network {
+      IpAddr::V4(_) => 32,
+      IpAddr::V6(_) => 128,
+    };
+    match prefix.unwrap_or(bits) {
+      prefix if prefix <= bits => Some(IpRange { network, prefix }),
+      _ => None,
+    }
+  }
+
+  fn contains(&self, ip: IpAddr) -> bool {
+    match (self.network, ip.to_canonical()) {
+      (IpAddr::V4(network), IpAddr::V4(ip)) => {
+        let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+        u32::from(network) & mask == u32::from(ip) & mask
+      }
+      (IpAddr::V6(network), IpAddr::V6(ip)) => {
+        let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+        u128::from(network) & mask == u128::from(ip) & mask
+      }
+      _ => false,
+    }
+  }
+}
+
+// `usize::is_multiple_of` needs Rust 1.87, newer than the tracker requires
+#[allow(clippy::manual_is_multiple_of)]
+fn parse_hex(entry: &str) -> Option<Vec<u8>> {
+  if entry.len() % 2 != 0 || !entry.bytes().all(|c| c.is_ascii_hexdigit()) {
+    return None;
+  }
+  (0..entry.len())
+    .step_by(2)
+    .map(|i| u8::from_str_radix(&entry[i..i + 2], 16).ok())
+    .collect()
+}
+
+/// Decodes a hash given in hex or base64, whatever its length.
+fn decode_hash(entry: &str) -> Option<Vec<u8>> {
+  parse_hex(entry).or_else(|| base64::decode(entry).ok())
+}
+
+/// Whether a decoded hash is as long as a site hash or a BitTorrent info
+/// hash, as any other length can never match an announced hash.
+fn is_hash_length(length: usize) -> bool {
+  matches!(length, 32 | ID_LENGTH)
+}
+
+/// Parses a hash given in hex or base64, which has to have the length of
+/// a site or a BitTorrent info hash.
+pub fn parse_hash(entry: &str) -> Option<Hash> {
+  decode_hash(entry)
+    .filter(|hash| is_hash_length(hash.len()))
+    .map(Hash)
+}
+
+/// IP ranges that may not connect and hashes that are neither stored nor
+/// answered.
+#[derive(Debug, Default)]
+pub struct Denylist {
+  ranges: Vec<IpRange>,
+  hashes: HashSet<Hash>,
+}
+
+impl Denylist {
+  /// Parses a denylist with one entry per line: an IP address, a CIDR
+  /// range, or a hash in hex or base64. Everything after a `#` is a comment.
+  pub fn parse(text: &str) -> Result<Denylist, String> {
+    let mut denylist = Denylist::default();
+    for (number, line) in text.lines().enumerate() {
+      let entry = line.split('#').next().unwrap().trim();
+      if entry.is_empty() {
+        continue;
+      }
+      if let Some(range) = IpRange::parse(entry) {
+        denylist.ranges.push(range);
+      } else if let Some(hash) = decode_hash(entry) {
+        if is_hash_length(hash.len()) {
+          denylist.hashes.insert(Hash(hash));
+        } else {
+          warn!(
+            "Skipping hash '{}' of {} bytes on line {}, hashes have 32 or {}",
+            entry,
+            hash.len(),
+            number + 1,
+            ID_LENGTH
+          );
+        }
+      } else {
+        return Err(format!("Invalid entry '{}' on line {}", entry, number + 1));
+      }
+    }
+    Ok(denylist)
+  }
+
+  pub fn load(path: &Path) -> Result<Denylist, String> {
+    let text = std::fs::read_to_string(path).map_err(|err| err.to_string())?;
+    Denylist::parse(&text)
+  }
+
+  pub fn denies_ip(&self, ip: IpAddr) -> bool {
+    self.ranges.iter().any(|range| range.contains(ip))
+  }
+
+  pub fn denies_hash(&self, hash: &Hash) -> bool {
+    self.hashes.contains(hash)
+  }
+}
+
+/// Loads the denylist file, if one is set, to be shared between the
+/// trackers and reloaded by `watch`.
+pub fn shared(path: Option<&Path>) -> Arc<RwLock<Denylist>> {
+  Arc::new(RwLock::new(match path {
+    Some(path) => Denylist::load(path).expect("Could not load denylist"),
+    None => Denylist::default(),
+  }))
+}
+
+fn reload(denylist: &RwLock<Denylist>, path: &Path) {
+  match Denylist::load(path) {
+    Ok(reloaded) => {
+      info!(
+        "Loaded denylist with {} ranges and {} hashes",
+        reloaded.ranges.len(),
+        reloaded.hashes.len()
+      );
+      *denylist.write().unwrap() = reloaded;
+    }
+    Err(err) => error!("Keeping previous denylist, could not load {:?}: {}", path, err),
+  }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+  std::fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+#[cfg(unix)]
+struct Hangup(tokio::signal::unix::Signal);
+
+#[cfg(unix)]
+impl Hangup {
+  fn new() -> Hangup {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    Hangup(signal(SignalKind::hangup()).expect("Could not listen for SIGHUP"))
+  }
+
+  async fn recv(&mut self) {
+    if self.0.recv().await.is_none() {
+      pending::<()>().await;
+    }
+  }
+}
+
+#[cfg(not(unix))]
+struct Hangup;
+
+#[cfg(not(unix))]
+impl Hangup {
+  fn new() -> Hangup {
+    Hangup
+  }
+
+  async fn recv(&mut self) {
+    pending::<()>().await;
+  }
+}
+
+/// Reloads the denylist whenever the file changes or on SIGHUP. A file that
+/// fails to load leaves the previous denylist in place.
+pub async fn watch(
+  denylist: Arc<RwLock<Denylist>>,
+  path: PathBuf,
+  interval: Duration,
+  mut shutdown: Shutdown,
+) {
+  let mut hangup = Hangup::new();
+  let mut last_modified = modified(&path);
+  loop {
+    tokio::select! {
+      _ = sleep(interval) => {
+        let modified = modified(&path);
+        if modified == last_modified {
+          continue;
+        }
+        last_modified = modified;
+      }
+      _ = hangup.recv() => info!("Received SIGHUP, reloading denylist"),
+      _ = shutdown.triggered() => return,
+    }
+    reload(&denylist, &path);
+  }
+}
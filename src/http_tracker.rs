@@ -0,0 +1,203 @@
+use std::collections::BTreeMap;
+
+use zeronet_peerdb::Hash;
+
+use crate::bittorrent::{
+  pack_peers, peer_ip, AnnounceRequest, Event, Swarm, TrackerConfig, ID_LENGTH,
+};
+
+/// A bencoded value as described in BEP 3.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Bencode {
+  Int(i64),
+  Bytes(Vec<u8>),
+  List(Vec<Bencode>),
+  Dict(BTreeMap<Vec<u8>, Bencode>),
+}
+
+impl Bencode {
+  pub fn dict(entries: Vec<(&str, Bencode)>) -> Bencode {
+    Bencode::Dict(
+      entries
+        .into_iter()
+        .map(|(key, value)| (key.as_bytes().to_vec(), value))
+        .collect(),
+    )
+  }
+
+  pub fn encode(&self) -> Vec<u8> {
+    let mut buf = Vec::new();
+    self.encode_into(&mut buf);
+    buf
+  }
+
+  fn encode_into(&self, buf: &mut Vec<u8>) {
+    match self {
+      Bencode::Int(int) => buf.extend(format!("i{}e", int).bytes()),
+      Bencode::Bytes(bytes) => encode_bytes(bytes, buf),
+      Bencode::List(list) => {
+        buf.push(b'l');
+        list.iter().for_each(|value| value.encode_into(buf));
+        buf.push(b'e');
+      }
+      Bencode::Dict(dict) => {
+        // BTreeMap iterates in the sorted key order that BEP 3 requires
+        buf.push(b'd');
+        for (key, value) in dict {
+          encode_bytes(key, buf);
+          value.encode_into(buf);
+        }
+        buf.push(b'e');
+      }
+    }
+  }
+}
+
+fn encode_bytes(bytes: &[u8], buf: &mut Vec<u8>) {
+  buf.extend(format!("{}:", bytes.len()).bytes());
+  buf.extend(bytes);
+}
+
+/// Splits a raw query string into its decoded parameters. Values are kept
+/// as bytes, since info hashes and peer ids are binary.
+pub fn parse_query(query: &str) -> Vec<(String, Vec<u8>)> {
+  query
+    .split('&')
+    .filter(|pair| !pair.is_empty())
+    .map(|pair| {
+      let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+      let key = String::from_utf8_lossy(&percent_decode(key)).into_owned();
+      (key, percent_decode(value))
+    })
+    .collect()
+}
+
+fn percent_decode(input: &str) -> Vec<u8> {
+  let bytes = input.as_bytes();
+  let mut decoded = Vec::with_capacity(bytes.len());
+  let mut i = 0;
+  while i < bytes.len() {
+    let hex = bytes
+      .get(i + 1..i + 3)
+      .and_then(|hex| std::str::from_utf8(hex).ok())
+      .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+    match (bytes[i], hex) {
+      (b'%', Some(byte)) => {
+        decoded.push(byte);
+        i += 3;
+        continue;
+      }
+      (b'+', _) => decoded.push(b' '),
+      (byte, _) => decoded.push(byte),
+    }
+    i += 1;
+  }
+  decoded
+}
+
+/// Reads an announce from the query of an HTTP request, together with
+/// whether it asks for a compact response. The `ip` parameter is ignored,
+/// peers are always stored with the address they connect from.
+pub fn parse_announce(query: &str) -> Result<(AnnounceRequest, bool), String> {
+  let params = parse_query(query);
+  let param = |name: &str| {
+    params
+      .iter()
+      .find(|(key, _)| key == name)
+      .map(|(_, value)| String::from_utf8_lossy(value).into_owned())
+  };
+
+  let info_hash = info_hashes(&params).into_iter().next();
+  let info_hash = info_hash.ok_or_else(|| "Missing or invalid info_hash".to_string())?;
+  match params.iter().find(|(key, _)| key == "peer_id") {
+    Some((_, peer_id)) if peer_id.len() == ID_LENGTH => {}
+    _ => return Err("Missing or invalid peer_id".to_string()),
+  }
+  let port = param("port")
+    .and_then(|port| port.parse().ok())
+    .ok_or_else(|| "Missing or invalid port".to_string())?;
+  let event = match param("event").as_deref() {
+    None | Some("") | Some("empty") => None,
+    Some("started") => Some(Event::Started),
+    Some("completed") => Some(Event::Completed),
+    Some("stopped") => Some(Event::Stopped),
+    Some(_) => return Err("Invalid event".to_string()),
+  };
+  let numwant = param("numwant").and_then(|numwant| numwant.parse().ok());
+  let compact = param("compact").as_deref() == Some("1");
+
+  let request = AnnounceRequest {
+    info_hash,
+    port,
+    event,
+    numwant,
+  };
+  Ok((request, compact))
+}
+
+/// Collects the `info_hash` parameters of a query, skipping any that are
+/// not 20 bytes long.
+pub fn info_hashes(params: &[(String, Vec<u8>)]) -> Vec<Hash> {
+  params
+    .iter()
+    .filter(|(key, value)| key == "info_hash" && value.len() == ID_LENGTH)
+    .map(|(_, value)| Hash(value.clone()))
+    .collect()
+}
+
+/// Builds the response to an announce. Compact responses list IPv4 peers
+/// in `peers` as in BEP 23 and IPv6 peers in `peers6` as in BEP 7.
+pub fn announce_response(config: &TrackerConfig, swarm: &Swarm, compact: bool) -> Bencode {
+  let peers = match compact {
+    true => Bencode::Bytes(pack_peers(&swarm.peers, false)),
+    false => Bencode::List(
+      swarm
+        .peers
+        .iter()
+        .filter_map(|peer| peer_ip(&peer.address))
+        .map(|(ip, port)| {
+          Bencode::dict(vec![
+            ("ip", Bencode::Bytes(ip.to_string().into_bytes())),
+            ("port", Bencode::Int(port as i64)),
+          ])
+        })
+        .collect(),
+    ),
+  };
+
+  let mut entries = vec![
+    ("interval", Bencode::Int(config.interval as i64)),
+    // ZeroNet peers serve the complete site, so they count as seeders
+    ("complete", Bencode::Int(swarm.size as i64)),
+    ("incomplete", Bencode::Int(0)),
+    ("peers", peers),
+  ];
+  let peers6 = pack_peers(&swarm.peers, true);
+  if compact && !peers6.is_empty() {
+    entries.push(("peers6", Bencode::Bytes(peers6)));
+  }
+  Bencode::dict(entries)
+}
+
+pub fn scrape_response(hashes: &[Hash], counts: &[usize]) -> Bencode {
+  let files = hashes
+    .iter()
+    .zip(counts)
+    .map(|(hash, count)| {
+      let stats = Bencode::dict(vec![
+        ("complete", Bencode::Int(*count as i64)),
+        ("downloaded", Bencode::Int(0)),
+        ("incomplete", Bencode::Int(0)),
+      ]);
+      (hash.0.clone(), stats)
+    })
+    .collect();
+  Bencode::dict(vec![("files", Bencode::Dict(files))])
+}
+
+pub fn failure_response(reason: &str) -> Bencode {
+  Bencode::dict(vec![(
+    "failure reason",
+    Bencode::Bytes(reason.as_bytes().to_vec()),
+  )])
+}
@@ -1,35 +1,62 @@
-use std::sync::{Arc, Mutex};
-use std::thread::sleep;
+use std::sync::{Arc, RwLock};
 use std::time::{Duration, SystemTime};
 
 use log::*;
+use tokio::time::sleep;
 
-use crate::shared_state::SharedState;
+#[cfg(feature = "metrics")]
+use crate::metrics;
+use crate::peer_db::Error;
+use crate::shared_state::{self, SharedState};
+use crate::shutdown::Shutdown;
 
-pub fn run(shared_state: Arc<Mutex<SharedState>>, interval: u16, timeout: u16) {
+pub async fn run(
+  shared_state: Arc<RwLock<SharedState>>,
+  interval: u16,
+  timeout: u16,
+  mut shutdown: Shutdown,
+) {
   loop {
-    sleep(Duration::from_secs(interval as u64));
-    let mut shared_state = shared_state.lock().unwrap();
-
-    let cutoff_timestamp = SystemTime::now() - Duration::from_secs(60 * timeout as u64);
-    if cutoff_timestamp < shared_state.start_time {
-      // Cutoff before start time of tracker. Wait with cleaning old peers
-      // to give them time to announce again.
-      continue;
+    tokio::select! {
+      _ = sleep(Duration::from_secs(interval as u64)) => {}
+      _ = shutdown.triggered() => {
+        info!("Stopping janitor");
+        return;
+      }
     }
+    shared_state::blocking(&shared_state, move |shared_state| {
+      clean_up(shared_state, timeout)
+    })
+    .await;
+  }
+}
 
-    let dead_peers = shared_state
-      .peer_db
-      .cleanup_peers(cutoff_timestamp)
-      .unwrap();
-    let stale_hashes = shared_state.peer_db.cleanup_hashes().unwrap();
+/// Removes the peers that timed out and the hashes left without peers.
+fn clean_up(shared_state: &RwLock<SharedState>, timeout: u16) {
+  let shared_state = shared_state::read(shared_state);
 
-    if dead_peers > 0 {
-      info!("Removed {} dead peers", dead_peers);
-    }
+  let cutoff_timestamp = SystemTime::now() - Duration::from_secs(60 * timeout as u64);
+  if cutoff_timestamp < shared_state.start_time {
+    // Cutoff before start time of tracker. Wait with cleaning old peers
+    // to give them time to announce again.
+    return;
+  }
 
-    if stale_hashes > 0 {
-      info!("Removed {} stale hashes", stale_hashes);
-    }
+  match shared_state.peer_db.cleanup_peers(cutoff_timestamp) {
+    Ok(0) => {}
+    Ok(dead_peers) => info!("Removed {} dead peers", dead_peers),
+    Err(err) => database_error("remove dead peers", err),
   }
+
+  match shared_state.peer_db.cleanup_hashes() {
+    Ok(0) => {}
+    Ok(stale_hashes) => info!("Removed {} stale hashes", stale_hashes),
+    Err(err) => database_error("remove stale hashes", err),
+  }
+}
+
+fn database_error(action: &str, err: Error) {
+  error!("Could not {}: {:?}", action, err);
+  #[cfg(feature = "metrics")]
+  metrics::DATABASE_ERRORS.inc();
 }
@@ -1,60 +1,150 @@
 #![feature(test)]
 #![cfg_attr(feature = "server", feature(proc_macro_hygiene, decl_macro))]
-use std::net::TcpListener;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
 use clap::{crate_name, crate_version};
 use log::*;
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
 
-use zeronet_peerdb::get_peer_db_type;
-
+mod address_filter;
+mod allowlist;
 mod args;
+mod bittorrent;
+mod budget;
+mod connection;
+mod denylist;
 mod janitor;
+mod peer_db;
 mod peer_handler;
+mod proxy_protocol;
+mod rate_limit;
+mod reachability;
 mod shared_state;
+mod sharded_peer_db;
+mod shutdown;
+mod udp_tracker;
 
+#[cfg(test)]
+mod benches;
+#[cfg(any(feature = "server", test))]
+mod http_tracker;
 #[cfg(feature = "metrics")]
 mod metrics;
+#[cfg(feature = "tor")]
+mod onion;
 #[cfg(feature = "server")]
 mod server;
+#[cfg(feature = "sql")]
+mod sqlite_peer_db;
+#[cfg(test)]
+mod tests;
+#[cfg(feature = "tls")]
+mod tls;
 
-use peer_handler::spawn_handler;
+use bittorrent::TrackerConfig;
+use peer_handler::{spawn_handler, HandlerConfig};
 use shared_state::SharedState;
+use shutdown::Shutdown;
 
 #[cfg(feature = "server")]
-fn start_server(shared_state: &Arc<Mutex<SharedState>>, port: u16) {
+fn start_server(
+  shared_state: &Arc<RwLock<SharedState>>,
+  port: u16,
+  tracker: TrackerConfig,
+  shutdown: Shutdown,
+) {
   let moved_state = shared_state.clone();
   std::thread::spawn(move || {
-    server::run(moved_state, port);
+    server::run(moved_state, port, tracker, shutdown);
   });
 }
 
-fn start_janitor(shared_state: &Arc<Mutex<SharedState>>, interval: u16, timeout: u16) {
+fn start_janitor(
+  shared_state: &Arc<RwLock<SharedState>>,
+  interval: u16,
+  timeout: u16,
+  shutdown: Shutdown,
+) -> JoinHandle<()> {
   info!(
     "Starting janitor with: interval={}s, timeout={}m",
     interval, timeout
   );
-  let moved_state = shared_state.clone();
-  std::thread::spawn(move || {
-    janitor::run(moved_state, interval, timeout);
-  });
+  tokio::spawn(janitor::run(shared_state.clone(), interval, timeout, shutdown))
+}
+
+async fn start_udp_tracker(
+  shared_state: &Arc<RwLock<SharedState>>,
+  address: &str,
+  port: u16,
+  config: TrackerConfig,
+  shutdown: Shutdown,
+) -> JoinHandle<()> {
+  let address_with_port = format!("{}:{}", address, port);
+  info!("Starting UDP tracker on {}", address_with_port);
+  let socket = UdpSocket::bind(&address_with_port).await.unwrap();
+  tokio::spawn(udp_tracker::run(shared_state.clone(), socket, config, shutdown))
 }
 
-fn start_listener(shared_state: &Arc<Mutex<SharedState>>, address: String, port: u16) {
+async fn start_listener(
+  shared_state: &Arc<RwLock<SharedState>>,
+  address: String,
+  port: u16,
+  max_connections: usize,
+  config: HandlerConfig,
+  mut shutdown: Shutdown,
+  drain_timeout: Duration,
+) {
   let address_with_port = format!("{}:{}", address, port);
-  info!("Starting listener on {}", address_with_port);
-  let listener = TcpListener::bind(&address_with_port).unwrap();
+  info!(
+    "Starting listener on {} with: max_connections={}",
+    address_with_port, max_connections
+  );
+  let listener = TcpListener::bind(&address_with_port).await.unwrap();
+  let permits = Arc::new(Semaphore::new(max_connections));
+  let config = Arc::new(config);
 
-  for stream in listener.incoming() {
-    if let Ok(stream) = stream {
-      spawn_handler(shared_state.clone(), stream);
-    } else {
-      error!("Could not handle incoming stream!");
+  loop {
+    // Wait for a free slot before accepting, so excess connections
+    // queue up in the backlog instead of consuming resources.
+    let accepted = tokio::select! {
+      accepted = async {
+        let permit = permits.clone().acquire_owned().await.unwrap();
+        (listener.accept().await, permit)
+      } => accepted,
+      _ = shutdown.triggered() => break,
+    };
+    match accepted {
+      (Ok((stream, _)), permit) => spawn_handler(
+        shared_state.clone(),
+        config.clone(),
+        stream,
+        permit,
+        shutdown.clone(),
+      ),
+      (Err(err), _) => error!("Could not handle incoming stream: {:?}", err),
     }
   }
+
+  drop(listener);
+  info!("Stopped accepting connections, waiting for open connections to finish");
+  // Handlers hold a permit each, so all permits being available
+  // means every connection has been closed.
+  let drained = timeout(drain_timeout, permits.acquire_many(max_connections as u32)).await;
+  if drained.is_err() {
+    warn!(
+      "Gave up on {} connections after {:?}",
+      max_connections - permits.available_permits(),
+      drain_timeout
+    );
+  }
 }
 
-fn main() {
+#[tokio::main]
+async fn main() {
   let args = args::get_arguments();
   pretty_env_logger::init_timed();
   info!(
@@ -63,13 +153,61 @@ fn main() {
     crate_version!(),
     env!("CARGO_PKG_REVISION"),
   );
-  info!("PeerDB type: {}", get_peer_db_type());
+  if !args.allowlist.is_empty() {
+    info!("Private tracker for {} allowlisted hashes", args.allowlist.len());
+  }
 
   let shared_state = SharedState::new(&args);
-  let shared_state = Arc::new(Mutex::new(shared_state));
+  info!("PeerDB type: {}", shared_state.peer_db.name());
+  let shared_state = Arc::new(RwLock::new(shared_state));
 
+  // Both trackers share the denylist, so one watcher reloads it for all
+  let config = HandlerConfig::from(&args);
+  let (trigger, shutdown) = shutdown::channel();
+  tokio::spawn(async move {
+    shutdown::signal().await;
+    info!("Shutting down");
+    let _ = trigger.send(true);
+  });
   #[cfg(feature = "server")]
-  start_server(&shared_state, args.rocket_port);
-  start_janitor(&shared_state, args.interval, args.timeout);
-  start_listener(&shared_state, args.address, args.port);
+  start_server(
+    &shared_state,
+    args.rocket_port,
+    TrackerConfig::new(&args, config.denylist.clone()),
+    shutdown.clone(),
+  );
+
+  let janitor = start_janitor(&shared_state, args.interval, args.timeout, shutdown.clone());
+  let udp_tracker = match args.udp_port {
+    0 => None,
+    port => {
+      let tracker = TrackerConfig::new(&args, config.denylist.clone());
+      Some(start_udp_tracker(&shared_state, &args.address, port, tracker, shutdown.clone()).await)
+    }
+  };
+  let denylist_watcher = args.denylist.clone().map(|path| {
+    let denylist = config.denylist.clone();
+    tokio::spawn(denylist::watch(denylist, path, denylist::RELOAD_INTERVAL, shutdown.clone()))
+  });
+  start_listener(
+    &shared_state,
+    args.address,
+    args.port,
+    args.max_connections,
+    config,
+    shutdown,
+    Duration::from_secs(args.shutdown_timeout as u64),
+  )
+  .await;
+  let _ = janitor.await;
+  if let Some(udp_tracker) = udp_tracker {
+    let _ = udp_tracker.await;
+  }
+  if let Some(denylist_watcher) = denylist_watcher {
+    let _ = denylist_watcher.await;
+  }
+
+  // Rocket 0.4 cannot be shut down and goes down with the process, its
+  // handlers turn requests away in the meantime.
+  info!("Shutdown complete");
 }
@@ -1,17 +1,36 @@
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, RwLock};
 
 use clap::crate_version;
 use lazy_static::lazy_static;
-use prometheus::{labels, opts, register_counter, register_int_counter, register_int_gauge, Counter, IntCounter, IntGauge};
-use zeronet_peerdb::get_peer_db_type;
+use prometheus::{labels, opts, register_counter, register_gauge, register_int_counter, register_int_gauge, register_int_gauge_vec, Counter, Gauge, IntCounter, IntGauge, IntGaugeVec};
 
-use crate::shared_state::SharedState;
+use crate::shared_state::{self, SharedState};
 
 lazy_static! {
   pub static ref PEER_GAUGE: IntGauge =
     register_int_gauge!("zn_tracker_peers", "Peers in database").unwrap();
   pub static ref HASH_GAUGE: IntGauge =
     register_int_gauge!("zn_tracker_hashes", "Hashes in database").unwrap();
+  pub static ref PEER_BUDGET_USAGE: Gauge = register_gauge!(
+    "zn_tracker_peer_budget_usage",
+    "Stored peers as a fraction of max_total_peers, 0 without a limit"
+  )
+  .unwrap();
+  pub static ref HASH_BUDGET_USAGE: Gauge = register_gauge!(
+    "zn_tracker_hash_budget_usage",
+    "Stored hashes as a fraction of max_total_hashes, 0 without a limit"
+  )
+  .unwrap();
+  pub static ref HASH_PEERS_BUDGET_USAGE: Gauge = register_gauge!(
+    "zn_tracker_hash_peers_budget_usage",
+    "Peers of the largest swarm as a fraction of max_hash_peers, 0 without a limit"
+  )
+  .unwrap();
+  pub static ref EVICTED_PEERS: IntCounter = register_int_counter!(
+    "zn_tracker_evicted_peers_total",
+    "Peers evicted to stay within the peer, hash and per-hash budgets"
+  )
+  .unwrap();
   pub static ref REQUEST_COUNTER: IntCounter =
     register_int_counter!("zn_tracker_requests_total", "Requests received").unwrap();
 
@@ -26,29 +45,94 @@ lazy_static! {
   )
   .unwrap();
 
+  pub static ref TIMED_OUT_CONNECTIONS: IntCounter = register_int_counter!(
+    "zn_tracker_timed_out_connections_total",
+    "Connections closed after waiting too long for a peer"
+  )
+  .unwrap();
+
+  pub static ref THROTTLED_CONNECTIONS: IntCounter = register_int_counter!(
+    "zn_tracker_throttled_connections_total",
+    "Connections refused for exceeding the per-IP connection limit"
+  )
+  .unwrap();
+  pub static ref THROTTLED_REQUESTS: IntCounter = register_int_counter!(
+    "zn_tracker_throttled_requests_total",
+    "Requests refused for exceeding the per-IP request rate"
+  )
+  .unwrap();
+
+  pub static ref DENIED_CONNECTIONS: IntCounter = register_int_counter!(
+    "zn_tracker_denied_connections_total",
+    "Connections refused because their IP is on the denylist"
+  )
+  .unwrap();
+  pub static ref DENIED_HASHES: IntCounter = register_int_counter!(
+    "zn_tracker_denied_hashes_total",
+    "Announced hashes ignored because they are on the denylist"
+  )
+  .unwrap();
+  pub static ref UNLISTED_HASHES: IntCounter = register_int_counter!(
+    "zn_tracker_unlisted_hashes_total",
+    "Announced hashes ignored because the tracker is private and they are not allowlisted"
+  )
+  .unwrap();
+  pub static ref REJECTED_ANNOUNCES: IntCounter = register_int_counter!(
+    "zn_tracker_rejected_announces_total",
+    "Announces rejected for exceeding the hash limits or mismatched onions"
+  )
+  .unwrap();
+
+  pub static ref DATABASE_ERRORS: IntCounter = register_int_counter!(
+    "zn_tracker_database_errors_total",
+    "Requests that failed because of a database error"
+  )
+  .unwrap();
+
   pub static ref CONNECTION_DURATION_SECONDS: Counter = register_counter!(
     "zn_tracker_connection_duration_seconds",
     "Sum of connection duration of closed connections"
   )
   .unwrap();
 
-  pub static ref VERSION_GAUGE: IntGauge = register_int_gauge!(opts!(
-    "zn_tracker_build_info",
-    "Build information",
-    labels! {
-      "version" => crate_version!(),
-      "revision" => env!("CARGO_PKG_REVISION"),
-      "peerdb_type" => get_peer_db_type(),
-      "rustc" => env!("CARGO_PKG_RUSTC"),
-    }
-  ))
+  // The database is chosen at startup, so its type is set with the gauge
+  pub static ref VERSION_GAUGE: IntGaugeVec = register_int_gauge_vec!(
+    opts!(
+      "zn_tracker_build_info",
+      "Build information",
+      labels! {
+        "version" => crate_version!(),
+        "revision" => env!("CARGO_PKG_REVISION"),
+        "rustc" => env!("CARGO_PKG_RUSTC"),
+      }
+    ),
+    &["peerdb_type"]
+  )
   .unwrap();
 }
 
-pub fn update_metrics(shared_state: &Arc<Mutex<SharedState>>) {
-  let shared_state = shared_state.lock().unwrap();
+/// How much of a budget is used, 1 when it is exhausted.
+fn usage(count: usize, max: Option<usize>) -> f64 {
+  max.map_or(0.0, |max| count as f64 / max as f64)
+}
+
+pub fn update_metrics(shared_state: &Arc<RwLock<SharedState>>) {
+  let shared_state = shared_state::read(shared_state);
+  let budget = shared_state.budget;
 
-  PEER_GAUGE.set(shared_state.peer_db.get_peer_count().unwrap_or(0) as i64);
-  HASH_GAUGE.set(shared_state.peer_db.get_hash_count().unwrap_or(0) as i64);
-  VERSION_GAUGE.set(1);
+  let peer_count = shared_state.peer_db.get_peer_count().unwrap_or(0);
+  let hash_count = shared_state.peer_db.get_hash_count().unwrap_or(0);
+  PEER_GAUGE.set(peer_count as i64);
+  HASH_GAUGE.set(hash_count as i64);
+  PEER_BUDGET_USAGE.set(usage(peer_count, budget.max_peers));
+  HASH_BUDGET_USAGE.set(usage(hash_count, budget.max_hashes));
+  // Finding the largest swarm goes through every hash, skip it without a limit
+  if budget.max_hash_peers.is_some() {
+    let hashes = shared_state.peer_db.get_hashes().unwrap_or_default();
+    let largest = hashes.iter().map(|(_, count)| *count).max().unwrap_or(0);
+    HASH_PEERS_BUDGET_USAGE.set(usage(largest, budget.max_hash_peers));
+  }
+  VERSION_GAUGE
+    .with_label_values(&[shared_state.peer_db.name()])
+    .set(1);
 }
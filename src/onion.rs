@@ -0,0 +1,60 @@
+use std::convert::TryInto;
+
+use base32::Alphabet;
+use ed25519_dalek::{Signature, VerifyingKey};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use sha3::{Digest, Sha3_256};
+
+const ONION_V3_VERSION: u8 = 3;
+const CHALLENGE_LENGTH: usize = 32;
+
+/// Generates a random string for peers to sign with their onion keys.
+pub fn new_challenge() -> String {
+  thread_rng()
+    .sample_iter(&Alphanumeric)
+    .take(CHALLENGE_LENGTH)
+    .map(char::from)
+    .collect()
+}
+
+/// Computes the two checksum bytes embedded in a v3 onion address.
+pub fn checksum(public_key: &[u8; 32]) -> [u8; 2] {
+  let mut hasher = Sha3_256::new();
+  hasher.update(b".onion checksum");
+  hasher.update(public_key);
+  hasher.update([ONION_V3_VERSION]);
+  let digest = hasher.finalize();
+  [digest[0], digest[1]]
+}
+
+/// Extracts the ed25519 public key from a v3 onion address,
+/// with or without the `.onion` suffix.
+pub fn public_key(onion: &str) -> Option<VerifyingKey> {
+  let onion = onion.trim_end_matches(".onion").to_uppercase();
+  let bytes = base32::decode(Alphabet::RFC4648 { padding: false }, &onion)?;
+  if bytes.len() != 35 || bytes[34] != ONION_V3_VERSION {
+    return None;
+  }
+
+  let public_key: [u8; 32] = bytes[..32].try_into().ok()?;
+  if checksum(&public_key) != bytes[32..34] {
+    return None;
+  }
+  VerifyingKey::from_bytes(&public_key).ok()
+}
+
+/// Checks that `sign` is a signature of `sign_this` made with the key
+/// of the given onion. Only v3 onions can be verified, since v2 addresses
+/// are a truncated hash and do not contain the public key.
+pub fn verify(onion: &str, sign_this: &str, sign: &[u8]) -> bool {
+  let public_key = match public_key(onion) {
+    Some(public_key) => public_key,
+    None => return false,
+  };
+  let sign = match Signature::from_slice(sign) {
+    Ok(sign) => sign,
+    Err(_) => return false,
+  };
+  public_key.verify_strict(sign_this.as_bytes(), &sign).is_ok()
+}
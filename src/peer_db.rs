@@ -0,0 +1,138 @@
+use std::fmt;
+use std::time::SystemTime;
+
+use zeronet_peerdb::{Hash, Peer};
+use zeronet_protocol::PeerAddr as Address;
+
+/// Number of batches the peers are evicted in to get below a hash budget,
+/// each followed by removing the hashes left without peers.
+const EVICTION_BATCHES: usize = 20;
+
+/// Errors of the peer stores. The in-memory store cannot fail, so without
+/// the `sql` feature there are none.
+#[derive(Debug)]
+pub enum Error {
+  #[cfg(feature = "sql")]
+  SQLite(rusqlite::Error),
+}
+
+impl fmt::Display for Error {
+  #[cfg_attr(not(feature = "sql"), allow(unused_variables))]
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      #[cfg(feature = "sql")]
+      Error::SQLite(ref err) => write!(f, "SQLite error: {}", err),
+    }
+  }
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(feature = "sql")]
+impl From<rusqlite::Error> for Error {
+  fn from(err: rusqlite::Error) -> Error {
+    Error::SQLite(err)
+  }
+}
+
+/// Storage for peers and the hashes they announced. Methods take `&self`
+/// and backends lock internally, so connections working on different
+/// hashes do not have to wait for each other.
+pub trait PeerStore: Send + Sync {
+  /// Name of the backend, as shown on the status page
+  fn name(&self) -> &'static str;
+
+  /// Adds hashes for a peer, adding the peer if it is new. Returns true if
+  /// the peer was known.
+  fn update_peer(&self, peer: &Peer, hashes: &[Hash]) -> Result<bool, Error>;
+  /// Removes a peer from all of its hashes, returning it if it was known.
+  fn remove_peer(&self, address: &Address) -> Result<Option<Peer>, Error>;
+  /// Removes a peer from a single hash, and the peer itself once it has no
+  /// hashes left. Returns true if the peer was stored for the hash.
+  fn unlink_peer(&self, hash: &Hash, address: &Address) -> Result<bool, Error>;
+
+  fn get_peer(&self, address: &Address) -> Result<Option<Peer>, Error>;
+  fn get_peers(&self) -> Result<Vec<Peer>, Error>;
+  fn get_peers_for_hash(&self, hash: &Hash) -> Result<Vec<Peer>, Error>;
+  /// Hashes the peer at the address is stored for
+  fn get_hashes_for_peer(&self, address: &Address) -> Result<Vec<Hash>, Error>;
+  /// Every hash along with its number of peers
+  #[cfg_attr(not(feature = "server"), allow(dead_code))]
+  fn get_hashes(&self) -> Result<Vec<(Hash, usize)>, Error>;
+
+  fn get_peer_count(&self) -> Result<usize, Error>;
+  fn get_hash_count(&self) -> Result<usize, Error>;
+
+  /// Removes peers that have not announced since `timestamp`.
+  fn cleanup_peers(&self, timestamp: SystemTime) -> Result<usize, Error>;
+  /// Removes hashes that have no peers left.
+  fn cleanup_hashes(&self) -> Result<usize, Error>;
+
+  /// Makes `hashes` the complete set of hashes of the peer, keeping the
+  /// `date_added` of a known peer. Returns true if the peer was known.
+  ///
+  /// Hashes the peer keeps are never unlinked on the way, and if storing
+  /// fails, the peer is left as it was.
+  fn replace_peer(&self, peer: &Peer, hashes: &[Hash]) -> Result<bool, Error>;
+
+  /// Stores several peers, each with its own hashes, in one batch. Returns
+  /// for every peer whether it was known.
+  fn update_peers(&self, updates: &[(Peer, Vec<Hash>)]) -> Result<Vec<bool>, Error>;
+
+  /// Looks up the peers of several hashes in one batch, in the order of
+  /// `hashes`. Hashes that are announced more than once are only looked up
+  /// once.
+  fn get_peers_for_hashes(&self, hashes: &[Hash]) -> Result<Vec<Vec<Peer>>, Error>;
+
+  /// Unlinks the peers of `hash` that were seen least recently until at
+  /// most `max_peers` are left. Returns the number of unlinked peers.
+  ///
+  /// The peers stay stored for their other hashes.
+  fn evict_from_hash(&self, hash: &Hash, max_peers: usize) -> Result<usize, Error> {
+    let mut peers = self.get_peers_for_hash(hash)?;
+    if peers.len() <= max_peers {
+      return Ok(0);
+    }
+    peers.sort_by_key(|peer| peer.last_seen);
+    let excess = peers.len() - max_peers;
+    for peer in &peers[..excess] {
+      self.unlink_peer(hash, &peer.address)?;
+    }
+    Ok(excess)
+  }
+
+  /// Removes the peers seen least recently until at most `max_peers` peers
+  /// and `max_hashes` hashes are left, dropping hashes without peers.
+  /// Returns the number of removed peers.
+  fn evict_oldest(&self, max_peers: usize, max_hashes: usize) -> Result<usize, Error> {
+    let peer_count = self.get_peer_count()?;
+    if peer_count <= max_peers && self.get_hash_count()? <= max_hashes {
+      return Ok(0);
+    }
+    let mut peers = self.get_peers()?;
+    peers.sort_by_key(|peer| peer.last_seen);
+    let mut peers = peers.into_iter();
+
+    let mut evicted = 0;
+    for peer in peers.by_ref().take(peer_count.saturating_sub(max_peers)) {
+      self.remove_peer(&peer.address)?;
+      evicted += 1;
+    }
+    self.cleanup_hashes()?;
+    // A hash only goes away with its last peer, so keep evicting in
+    // batches until enough hashes are left without peers.
+    let batch_size = (peer_count / EVICTION_BATCHES).max(1);
+    while self.get_hash_count()? > max_hashes {
+      let batch: Vec<Peer> = peers.by_ref().take(batch_size).collect();
+      if batch.is_empty() {
+        break;
+      }
+      for peer in batch.iter() {
+        self.remove_peer(&peer.address)?;
+      }
+      evicted += batch.len();
+      self.cleanup_hashes()?;
+    }
+    Ok(evicted)
+  }
+}
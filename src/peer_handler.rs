@@ -1,75 +1,617 @@
-use std::collections::HashMap;
-use std::net::TcpStream;
-use std::sync::{Arc, Mutex};
-use std::time::SystemTime;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime};
 
-use futures::executor::block_on;
+use clap::crate_version;
+use futures::future::BoxFuture;
 use log::*;
+use rand::distributions::Alphanumeric;
+use rand::seq::SliceRandom;
+use rand::{thread_rng, Rng};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
 use serde_bytes::ByteBuf;
+use tokio::net::TcpStream;
+use tokio::sync::OwnedSemaphorePermit;
+use tokio::time::timeout;
 use zeronet_protocol::{
   error::Error,
   message::{templates, Request},
-  PeerAddr as Address, ZeroConnection,
+  PeerAddr as Address,
 };
 use zeronet_peerdb::{Hash, Peer};
 
+use crate::address_filter::AddressFilter;
+use crate::allowlist::Allowlist;
+use crate::args::Args;
+use crate::connection::Connection;
+use crate::denylist::{self, Denylist};
 #[cfg(feature = "metrics")]
 use crate::metrics;
-use crate::shared_state::SharedState;
+#[cfg(feature = "tor")]
+use crate::onion;
+use crate::peer_db::Error as PeerDBError;
+use crate::proxy_protocol;
+use crate::rate_limit::RateLimiter;
+use crate::reachability::ReachabilityChecker;
+use crate::shared_state::{self, SharedState};
+use crate::shutdown::Shutdown;
+#[cfg(feature = "tls")]
+use crate::tls::{self, TlsContext};
 
-pub fn spawn_handler(shared_state: Arc<Mutex<SharedState>>, stream: TcpStream) {
-  if let Ok(address) = stream.peer_addr() {
-    info!("Incoming connection from {}", address);
-    let address = Address::from(address);
+/// How long a refused connection is kept open to tell the peer why.
+const REFUSE_TIMEOUT: Duration = Duration::from_secs(5);
 
-    std::thread::spawn(move || {
-      let connection =
-        ZeroConnection::new(Box::new(stream.try_clone().unwrap()), Box::new(stream)).unwrap();
-      let mut handler = Handler::create(shared_state.clone(), connection, address);
+pub fn spawn_handler(
+  shared_state: Arc<RwLock<SharedState>>,
+  config: Arc<HandlerConfig>,
+  mut stream: TcpStream,
+  permit: OwnedSemaphorePermit,
+  shutdown: Shutdown,
+) {
+  tokio::spawn(async move {
+    let address = match peer_address(&config, &mut stream).await {
+      Some(address) => address,
+      None => return,
+    };
+    info!("Incoming connection from {}", address);
 
+    if config.denylist.read().unwrap().denies_ip(address.ip()) {
+      info!("Refusing connection from denied address {}", address);
       #[cfg(feature = "metrics")]
-      metrics::OPENED_CONNECTIONS.inc();
-      #[cfg(feature = "metrics")]
-      let start_time = SystemTime::now();
+      metrics::DENIED_CONNECTIONS.inc();
+      return;
+    }
+
+    let connection_guard = match config.rate_limiter.acquire_connection(address.ip()) {
+      Some(guard) => guard,
+      None => {
+        warn!("Too many connections from {}", address.ip());
+        #[cfg(feature = "metrics")]
+        metrics::THROTTLED_CONNECTIONS.inc();
+        refuse(stream, "Too many connections").await;
+        return;
+      }
+    };
 
-      handler.run();
+    let mut handler = Handler::create(shared_state, config, stream, address, shutdown);
 
-      #[cfg(feature = "metrics")]
-      metrics::CLOSED_CONNECTIONS.inc();
-      #[cfg(feature = "metrics")]
-      metrics::CONNECTION_DURATION_SECONDS
-        .inc_by(SystemTime::now().duration_since(start_time).map(|d| d.as_secs_f64()).unwrap_or(0.))
-    });
-  } else {
-    error!("Could not detect address for stream.");
+    #[cfg(feature = "metrics")]
+    metrics::OPENED_CONNECTIONS.inc();
+    #[cfg(feature = "metrics")]
+    let start_time = SystemTime::now();
+
+    handler.run().await;
+
+    #[cfg(feature = "metrics")]
+    metrics::CLOSED_CONNECTIONS.inc();
+    #[cfg(feature = "metrics")]
+    metrics::CONNECTION_DURATION_SECONDS
+      .inc_by(SystemTime::now().duration_since(start_time).map(|d| d.as_secs_f64()).unwrap_or(0.));
+
+    drop(connection_guard);
+    drop(permit);
+  });
+}
+
+/// Answers the first request of a connection that is not handled with an
+/// error, so the peer learns why it is closed instead of guessing at a
+/// network failure.
+async fn refuse(stream: TcpStream, message: &str) {
+  let mut connection = Connection::new(stream);
+  if let Ok(Ok(req)) = timeout(REFUSE_TIMEOUT, connection.recv()).await {
+    let body = templates::Error {
+      error: message.to_string(),
+    };
+    let response = timeout(REFUSE_TIMEOUT, connection.respond(req.req_id, body));
+    if let Ok(Err(err)) = response.await {
+      debug!("Could not refuse connection: {:?}", err);
+    }
+  }
+}
+
+/// Finds the address of the peer. Connections from trusted proxies have to
+/// start with a PROXY protocol header naming the client, which is skipped
+/// before the stream is handed to the handler.
+async fn peer_address(config: &HandlerConfig, stream: &mut TcpStream) -> Option<SocketAddr> {
+  let address = match stream.peer_addr() {
+    Ok(address) => address,
+    Err(_) => {
+      error!("Could not detect address for stream.");
+      return None;
+    }
+  };
+  if !config.trusted_proxies.contains(&address.ip().to_canonical()) {
+    return Some(address);
+  }
+
+  let header = within(config.read_timeout, proxy_protocol::read_header(stream))
+    .await
+    .unwrap_or_else(|| Err(io::ErrorKind::TimedOut.into()));
+  match header {
+    Ok(Some(client)) => {
+      trace!("Connection from {} proxied by {}", client, address);
+      Some(client)
+    }
+    Ok(None) => Some(address),
+    Err(err) => {
+      warn!("Invalid PROXY protocol header from {}: {:?}", address, err);
+      None
+    }
+  }
+}
+
+/// Settings that apply to every connection handled by the listener.
+pub struct HandlerConfig {
+  /// The peer_id the tracker introduces itself with
+  pub peer_id:         String,
+  /// Port the tracker accepts peer connections on
+  pub port:            u16,
+  /// Upper bound on the number of peers returned per hash and address type
+  pub max_peers:       usize,
+  /// Upper bound on the number of hashes in a single announce
+  pub max_hashes:      Option<usize>,
+  /// Upper bound on the number of distinct hashes stored for a peer
+  pub max_peer_hashes: Option<usize>,
+  /// Rejects announces on connections that have not sent a handshake
+  pub strict:          bool,
+  pub address_filter:  AddressFilter,
+  /// Dials announced clearnet peers back when enabled
+  pub reachability:    Option<ReachabilityChecker>,
+  pub rate_limiter:    RateLimiter,
+  /// Proxies whose connections start with a PROXY protocol header
+  pub trusted_proxies: Vec<IpAddr>,
+  /// Refused IP ranges and hashes, replaced when the file is reloaded
+  pub denylist:        Arc<RwLock<Denylist>>,
+  /// The only hashes stored and answered, unless empty
+  pub allowlist:       Allowlist,
+  /// How long to wait for the next request before closing the connection
+  pub idle_timeout:    Option<Duration>,
+  /// How long a peer may take to finish sending a request it started
+  pub read_timeout:    Option<Duration>,
+  /// How long a peer may take to receive a response
+  pub write_timeout:   Option<Duration>,
+  #[cfg(feature = "tls")]
+  pub tls:             TlsContext,
+}
+
+impl From<&Args> for HandlerConfig {
+  fn from(args: &Args) -> HandlerConfig {
+    HandlerConfig {
+      peer_id:         args.peer_id.clone().unwrap_or_else(generate_peer_id),
+      port:            args.port,
+      max_peers:       args.max_peers,
+      max_hashes:      limit(args.max_announce_hashes),
+      max_peer_hashes: limit(args.max_peer_hashes),
+      strict:          args.require_handshake,
+      address_filter:  AddressFilter::new(args.allowed_addresses.clone()),
+      reachability:    match args.verify_reachability {
+        true => Some(ReachabilityChecker::new(Duration::from_secs(
+          60 * args.reachability_cache as u64,
+        ))),
+        false => None,
+      },
+      rate_limiter:    RateLimiter::new(
+        args.max_connections_per_ip,
+        args.request_rate,
+        args.request_burst,
+      ),
+      trusted_proxies: args.trusted_proxies.clone(),
+      denylist:        denylist::shared(args.denylist.as_deref()),
+      allowlist:       Allowlist::new(args.allowlist.clone()),
+      idle_timeout:    timeout_from_secs(args.idle_timeout),
+      read_timeout:    timeout_from_secs(args.read_timeout),
+      write_timeout:   timeout_from_secs(args.write_timeout),
+      #[cfg(feature = "tls")]
+      tls:             TlsContext::new(args.tls_cert.clone(), args.tls_key.clone())
+        .expect("Could not set up TLS"),
+    }
+  }
+}
+
+/// Runs the future for at most the given duration, returning `None` if it
+/// did not finish in time.
+async fn within<F: Future>(duration: Option<Duration>, future: F) -> Option<F::Output> {
+  match duration {
+    Some(duration) => timeout(duration, future).await.ok(),
+    None => Some(future.await),
+  }
+}
+
+/// Generates a peer_id in the style of ZeroNet's `-ZN0056-`, with `ZT`
+/// marking the tracker followed by the digits of its version.
+fn generate_peer_id() -> String {
+  let version: String = crate_version!().chars().filter(char::is_ascii_digit).collect();
+  let random: String = thread_rng()
+    .sample_iter(&Alphanumeric)
+    .take(12)
+    .map(char::from)
+    .collect();
+  format!("-ZT{:0>4.4}-{}", version, random)
+}
+
+/// Zero disables a timeout.
+fn timeout_from_secs(secs: u16) -> Option<Duration> {
+  match secs {
+    0 => None,
+    secs => Some(Duration::from_secs(secs as u64)),
+  }
+}
+
+fn limit(max: usize) -> Option<usize> {
+  match max {
+    0 => None,
+    max => Some(max),
+  }
+}
+
+/// Packs a random sample of at most `limit` peers of each requested type.
+/// Shuffling spreads the load across the swarm instead of always handing
+/// out the same peers first.
+pub fn pack_peers(
+  mut peers: Vec<Peer>,
+  need_types: &[String],
+  limit: usize,
+) -> templates::AnnouncePeers {
+  let need = |peer_type: &str| need_types.iter().any(|t| t == peer_type);
+  // Need to check 'ip4' for backwards compat
+  let need_ipv4 = need("ipv4") || need("ip4");
+  let need_ipv6 = need("ipv6");
+  #[cfg(feature = "tor")]
+  let need_onion = need("onion");
+  #[cfg(feature = "i2p")]
+  let need_i2p = need("i2p");
+
+  peers.shuffle(&mut thread_rng());
+
+  let mut packed = templates::AnnouncePeers::default();
+  for peer in peers {
+    let bytes = ByteBuf::from(peer.address.pack());
+    match peer.address {
+      Address::IPV4(_, _) => {
+        if need_ipv4 && packed.ip_v4.len() < limit {
+          packed.ip_v4.push(bytes);
+        }
+      }
+      Address::IPV6(_, _) => {
+        if need_ipv6 && packed.ip_v6.len() < limit {
+          packed.ip_v6.push(bytes);
+        }
+      }
+      #[cfg(feature = "tor")]
+      Address::OnionV2(_, _) | Address::OnionV3(_, _) => {
+        if need_onion && packed.onion_v2.len() < limit {
+          packed.onion_v2.push(bytes);
+        }
+      }
+      #[cfg(feature = "i2p")]
+      Address::I2PB32(_, _) => {
+        if need_i2p && packed.i2p_b32.len() < limit {
+          packed.i2p_b32.push(bytes);
+        }
+      }
+    }
+  }
+  packed
+}
+
+/// Name of the address type as used in `add` and `need_types`.
+fn address_type(address: &Address) -> &'static str {
+  match address {
+    Address::IPV4(_, _) => "ipv4",
+    Address::IPV6(_, _) => "ipv6",
+    #[cfg(feature = "tor")]
+    Address::OnionV2(_, _) | Address::OnionV3(_, _) => "onion",
+    #[cfg(feature = "i2p")]
+    Address::I2PB32(_, _) => "i2p",
+  }
+}
+
+/// Whether the address the peer connected from should be stored. Peers list
+/// the address types to add, older ones that send none only get their own
+/// address stored if they do not announce onions.
+///
+/// Only clearnet addresses are proven by the connection itself, onions are
+/// stored from the signed `onions` of an announce instead.
+fn adds_own_address(announce: &templates::Announce, address: &Address) -> bool {
+  if !address.is_clearnet() {
+    return false;
+  }
+  if announce.add.is_empty() {
+    return announce.onions.is_empty();
+  }
+  let own_type = address_type(address);
+  announce
+    .add
+    .iter()
+    .any(|t| t == own_type || (t == "ip4" && own_type == "ipv4"))
+}
+
+/// When the peer at the address was stored first, now for a new peer.
+fn date_added(shared_state: &SharedState, address: &Address) -> Result<SystemTime, PeerDBError> {
+  Ok(match shared_state.peer_db.get_peer(address)? {
+    Some(peer) => peer.date_added,
+    None => SystemTime::now(),
+  })
+}
+
+/// Counts the distinct hashes stored for the peer at `address` once the
+/// announce is stored, including those it announced on other connections.
+fn peer_hash_count(
+  shared_state: &SharedState,
+  address: &Address,
+  announce: &templates::Announce,
+  hashes: &[Option<Hash>],
+) -> Result<usize, PeerDBError> {
+  let mut distinct: HashSet<&Hash> = hashes.iter().flatten().collect();
+  // The announce replaces the stored hashes
+  if announce.delete {
+    return Ok(distinct.len());
+  }
+  let stored = shared_state.peer_db.get_hashes_for_peer(address)?;
+  distinct.extend(stored.iter());
+  Ok(distinct.len())
+}
+
+/// Stores the announcing peer for the announced hashes.
+fn store_announce(
+  shared_state: &SharedState,
+  config: &HandlerConfig,
+  announce: &templates::Announce,
+  hashes: &[Option<Hash>],
+  address: &Address,
+  reachable: bool,
+  onions_verified: bool,
+) -> Result<(), PeerDBError> {
+  if adds_own_address(announce, address) {
+    let peer = Peer {
+      date_added: date_added(shared_state, address)?,
+      address:    address.clone(),
+      last_seen: SystemTime::now(),
+    };
+    let peer_address = peer.address.to_string();
+    let hashes: Vec<Hash> = hashes.iter().flatten().cloned().collect();
+
+    if !config.address_filter.accepts(&peer.address) {
+      trace!("Ignoring peer {} outside of allowed addresses", peer_address);
+    } else if !reachable {
+      info!("Not listing unreachable peer {}", peer_address);
+    } else {
+      trace!("Updating peer {}", peer_address);
+      let peer_already_known = match announce.delete {
+        true => shared_state.peer_db.replace_peer(&peer, &hashes)?,
+        false => shared_state.peer_db.update_peer(&peer, &hashes)?,
+      };
+      match peer_already_known {
+        true => info!("Updated peer {} for {} hashes", peer_address, hashes.len()),
+        false => info!("Added peer {} for {} hashes", peer_address, hashes.len()),
+      }
+    }
+  }
+
+  if adds_onions(announce) && onions_verified {
+    let mut onion_hashes = HashMap::<String, Vec<Hash>>::new();
+    announce
+      .onions
+      .iter()
+      .zip(hashes.iter())
+      .filter_map(|(onion, hash)| Some((onion, hash.as_ref()?)))
+      .for_each(|(onion, hash)| {
+        if let Some(hashes) = onion_hashes.get_mut(onion) {
+          hashes.push(hash.clone());
+        } else {
+          onion_hashes.insert(onion.to_string(), vec![hash.clone()]);
+        }
+      });
+    let mut updates: Vec<(Peer, Vec<Hash>)> = Vec::with_capacity(onion_hashes.len());
+    for (onion, hashes) in onion_hashes {
+      let address = match Address::parse(format!("{}.onion:{}", onion, announce.port)) {
+        Ok(address) => address,
+        Err(_) => continue,
+      };
+      let peer = Peer {
+        date_added: date_added(shared_state, &address)?,
+        address,
+        last_seen: SystemTime::now(),
+      };
+      updates.push((peer, hashes));
+    }
+    let t = Instant::now();
+    match announce.delete {
+      true => {
+        for (peer, hashes) in updates.iter() {
+          shared_state.peer_db.replace_peer(peer, hashes)?;
+        }
+      }
+      false => {
+        shared_state.peer_db.update_peers(&updates)?;
+      }
+    }
+    trace!(
+      "Updated {} onions in {:?}",
+      updates.len(),
+      t.elapsed(),
+    );
+    info!("Added onions for {} hashes", announce.onions.len());
+  } else if adds_onions(announce) {
+    info!("Onions from {} are not signed", address);
+  }
+  Ok(())
+}
+
+/// Collects the peers to return for each hash.
+fn find_peers(
+  shared_state: &SharedState,
+  max_peers: usize,
+  announce: &templates::Announce,
+  hashes: &[Option<Hash>],
+) -> Result<Vec<templates::AnnouncePeers>, PeerDBError> {
+  let limit = match announce.need_num {
+    0 => max_peers,
+    need_num => need_num.min(max_peers),
+  };
+  let allowed: Vec<Hash> = hashes.iter().flatten().cloned().collect();
+  let mut hash_peers = shared_state
+    .peer_db
+    .get_peers_for_hashes(&allowed)?
+    .into_iter();
+  // Denied hashes get an empty list
+  Ok(
+    hashes
+      .iter()
+      .map(|hash| match hash {
+        Some(_) => pack_peers(hash_peers.next().unwrap(), &announce.need_types, limit),
+        None => templates::AnnouncePeers::default(),
+      })
+      .collect(),
+  )
+}
+
+/// Whether the onions in the announce should be stored.
+fn adds_onions(announce: &templates::Announce) -> bool {
+  !announce.onions.is_empty()
+    && (announce.add.is_empty() || announce.add.iter().any(|t| t == "onion"))
+}
+
+/// The announce response extended with the onion signing challenge,
+/// which `templates::AnnounceResponse` has no field for.
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct AnnounceResponse {
+  #[serde(flatten)]
+  pub response:        templates::AnnounceResponse,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub onion_sign_this: Option<String>,
+}
+
+/// Handles a single request on behalf of a connection.
+type Command = for<'a> fn(&'a mut Handler, Request) -> BoxFuture<'a, ()>;
+
+/// Commands understood by the tracker, new commands only need an entry here.
+const COMMANDS: &[(&str, Command)] = &[
+  ("handshake", |handler, req| Box::pin(handler.handle_handshake(req))),
+  ("announce", |handler, req| Box::pin(handler.handle_announce(req))),
+  ("ping", |handler, req| Box::pin(handler.handle_ping(req))),
+];
+
+/// Lifecycle of a peer connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+  /// Nothing received yet
+  New,
+  /// A valid handshake was received
+  Handshaken,
+  /// The peer has announced
+  Active,
+}
+
+impl ConnectionState {
+  /// A valid handshake is accepted in every state, peers may repeat it.
+  pub fn handshake(self) -> ConnectionState {
+    ConnectionState::Handshaken
+  }
+
+  /// Returns the state after an announce, or `None` if the announce
+  /// has to be rejected because `strict` requires a handshake first.
+  pub fn announce(self, strict: bool) -> Option<ConnectionState> {
+    match self {
+      ConnectionState::New if strict => None,
+      _ => Some(ConnectionState::Active),
+    }
+  }
+}
+
+/// What a peer told about itself in its handshake.
+struct ClientInfo {
+  peer_id: String,
+  version: String,
+  rev:     usize,
+}
+
+impl fmt::Display for ClientInfo {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{} v{} r{}", self.peer_id, self.version, self.rev)
   }
 }
 
 struct Handler {
-  peer_id:      String,
-  shared_state: Arc<Mutex<SharedState>>,
-  connection:   ZeroConnection,
-  address:      Address,
+  shared_state:    Arc<RwLock<SharedState>>,
+  config:          Arc<HandlerConfig>,
+  connection:      Connection,
+  address:         Address,
+  /// IP the connection came from, requests are rate limited by it
+  ip:              IpAddr,
+  shutdown:        Shutdown,
+  state:           ConnectionState,
+  /// Set once the peer sent a handshake
+  client:          Option<ClientInfo>,
+  /// Whether the peer claimed its fileserver port is open
+  port_opened:     Option<bool>,
+  /// Challenge issued to this connection for proving onion ownership
+  #[cfg(feature = "tor")]
+  onion_sign_this: Option<String>,
 }
 
 impl Handler {
   pub fn create(
-    shared_state: Arc<Mutex<SharedState>>,
-    connection: ZeroConnection,
-    address: Address,
+    shared_state: Arc<RwLock<SharedState>>,
+    config: Arc<HandlerConfig>,
+    stream: TcpStream,
+    socket_address: SocketAddr,
+    shutdown: Shutdown,
   ) -> Handler {
     Handler {
-      peer_id: String::new(),
       shared_state,
-      connection,
-      address,
+      config,
+      connection: Connection::new(stream),
+      address: Address::from(socket_address),
+      ip: socket_address.ip(),
+      shutdown,
+      state: ConnectionState::New,
+      client: None,
+      port_opened: None,
+      #[cfg(feature = "tor")]
+      onion_sign_this: None,
     }
   }
 
-  pub fn run(&mut self) {
+  pub async fn run(&mut self) {
     loop {
       trace!("Waiting for data...");
-      let req = block_on(self.connection.recv());
+      // A request that is already being handled is finished on shutdown,
+      // only waiting for the next one is given up.
+      let waited = tokio::select! {
+        waited = within(self.config.idle_timeout, self.connection.wait_for_data()) => waited,
+        _ = self.shutdown.triggered() => {
+          info!("Closing connection for shutdown: {}", self.address);
+          break;
+        }
+      };
+      // The idle timeout covers the wait for a request, the read timeout
+      // only starts once its first bytes arrived.
+      let req = match waited {
+        Some(Ok(())) => within(self.config.read_timeout, self.connection.recv()).await,
+        Some(Err(err)) => Some(Err(err)),
+        None => {
+          info!("Connection timed out: {}", self.address);
+          #[cfg(feature = "metrics")]
+          metrics::TIMED_OUT_CONNECTIONS.inc();
+          break;
+        }
+      };
+      let req = match req {
+        Some(req) => req,
+        None => {
+          warn!("Request from {} not completed in time", self.address);
+          #[cfg(feature = "metrics")]
+          metrics::TIMED_OUT_CONNECTIONS.inc();
+          break;
+        }
+      };
 
       #[cfg(feature = "metrics")]
       metrics::REQUEST_COUNTER.inc();
@@ -77,7 +619,7 @@ impl Handler {
       if let Err(err) = req {
         match err {
           Error::Io(_) | Error::ConnectionClosed => {
-            info!("Connection terminated: {}", self.address.to_string())
+            info!("Connection terminated: {}", self.peer_name())
           }
           _ => error!("Encountered unexpected error: {:?}", err),
         }
@@ -86,212 +628,353 @@ impl Handler {
       let req = req.unwrap();
       let cmd = req.cmd.clone();
 
-      info!("Received {} from {}", cmd, self.address.to_string());
-      let t = SystemTime::now();
-      match cmd.as_str() {
-        "handshake" => self.handle_handshake(req),
-        "announce" => self.handle_announce(req),
-        _ => self.handle_unsupported(req.req_id),
+      info!("Received {} from {}", cmd, self.peer_name());
+      if !self.config.rate_limiter.allow_request(self.ip) {
+        warn!("Throttling {} from {}", cmd, self.address);
+        #[cfg(feature = "metrics")]
+        metrics::THROTTLED_REQUESTS.inc();
+        self
+          .handle_error(req.req_id, "Too many requests".to_string())
+          .await;
+        continue;
+      }
+
+      let t = Instant::now();
+      match COMMANDS.iter().find(|(name, _)| *name == cmd) {
+        Some((_, command)) => command(self, req).await,
+        None => self.handle_unsupported(req.req_id).await,
       };
       info!(
         "Handled {} from {} in {:?}",
         cmd,
         self.address.to_string(),
-        SystemTime::now().duration_since(t).unwrap()
+        t.elapsed()
       );
     }
   }
 
-  fn handle_handshake(&mut self, req: Request) {
+  /// The address of the peer, along with what it told about itself.
+  fn peer_name(&self) -> String {
+    match &self.client {
+      Some(client) => format!("{} ({})", self.address, client),
+      None => self.address.to_string(),
+    }
+  }
+
+  async fn handle_handshake(&mut self, req: Request) {
     trace!("Received handshake: {:?}", req);
     let handshake: Result<templates::Handshake, _> = req.body();
     let handshake = match handshake {
       Ok(handshake) => handshake,
       Err(err) => {
-        return self.handle_invalid(req.req_id, err);
+        return self.handle_invalid(req.req_id, err).await;
       }
     };
 
-    if let Some(onion) = handshake.onion {
-      match Address::parse(format!("{}.onion:{}", onion, handshake.fileserver_port)) {
-        Ok(address) => self.address = address,
-        Err(err) => {
-          error!("Could not parse address: {:?}", err);
-          return;
-        }
+    info!(
+      "Handshake from {} with peer_id={}, version={}, rev={}",
+      self.address, handshake.peer_id, handshake.version, handshake.rev
+    );
+    self.client = Some(ClientInfo {
+      peer_id: handshake.peer_id.clone(),
+      version: handshake.version.clone(),
+      rev:     handshake.rev,
+    });
+    self.port_opened = handshake.port_opened;
+
+    self.state = self.state.handshake();
+
+    // Keeps the protocol revision from `Handshake::new`, which tells
+    // clients what the tracker understands.
+    let mut body = templates::Handshake::new();
+    body.peer_id = self.config.peer_id.clone();
+    body.version = crate_version!().to_string();
+    body.fileserver_port = self.config.port as usize;
+    body.port_opened = Some(true);
+    // Lets peers behind NAT learn their external address
+    body.target_address = Some(self.ip.to_string());
+
+    #[cfg(feature = "tls")]
+    let start_tls =
+      self.connection.is_plain() && handshake.crypt_supported.iter().any(|c| c == tls::CRYPT);
+    #[cfg(feature = "tls")]
+    {
+      body.crypt_supported = vec![tls::CRYPT.to_string()];
+      if start_tls {
+        body.crypt = Some(tls::CRYPT.to_string());
       }
     }
 
-    let mut body = templates::Handshake::new();
-    body.peer_id = self.peer_id.clone();
     trace!("Response: {:?}", body);
-    let response = self.connection.respond(req.req_id, body);
-    let result = block_on(response);
-    if let Err(err) = result {
-      error!("Encountered error responding to handshake: {:?}", err);
+    let result = self.respond(req.req_id, body).await;
+    match result {
+      Err(err) => error!("Encountered error responding to handshake: {:?}", err),
+      #[cfg(feature = "tls")]
+      Ok(()) if start_tls => self.start_tls().await,
+      Ok(()) => {}
+    }
+  }
+
+  /// Wraps the connection in TLS, the client starts its side of the
+  /// TLS handshake as soon as it receives our handshake response.
+  #[cfg(feature = "tls")]
+  async fn start_tls(&mut self) {
+    // Leaves the connection closed on failure, ending the handler
+    let result = within(self.config.read_timeout, self.connection.start_tls(&self.config.tls))
+      .await
+      .unwrap_or_else(|| Err("TLS handshake timed out".to_string()));
+    match result {
+      Ok(()) => info!("Encrypted connection with {}", self.address),
+      Err(err) => error!("Could not start TLS with {}: {}", self.address, err),
     }
   }
 
-  fn handle_announce(&mut self, req: Request) {
+  async fn handle_announce(&mut self, req: Request) {
+    let next_state = match self.state.announce(self.config.strict) {
+      Some(state) => state,
+      None => {
+        warn!("Announce before handshake from {}", self.address);
+        let message = "Handshake required before announce".to_string();
+        return self.handle_error(req.req_id, message).await;
+      }
+    };
+
     let announce: Result<templates::Announce, _> = req.body();
     let announce = match announce {
       Ok(announce) => announce,
-      Err(err) => return self.handle_invalid(req.req_id, err),
+      Err(err) => return self.handle_invalid(req.req_id, err).await,
     };
 
-    let announce = announce;
     trace!("Announce: {:?}", announce);
 
-    let mut body = templates::AnnounceResponse::default();
-    {
-      let mut shared_state = self.shared_state.lock().unwrap();
-
-      let address = self.address.with_port(announce.port as u16);
-      if announce.delete {
-        trace!("Deleting peer {}", &address);
-        shared_state
-          .peer_db
-          .remove_peer(&address)
-          .expect("Could not remove peer");
+    if let Err(message) = self.check_announce(&announce) {
+      warn!("Rejecting announce from {}: {}", self.address, message);
+      #[cfg(feature = "metrics")]
+      metrics::REJECTED_ANNOUNCES.inc();
+      return self.handle_error(req.req_id, message).await;
+    }
+
+    #[cfg(feature = "tor")]
+    let onions_verified = {
+      let verified = self.onions_verified(&announce);
+      if !verified && !announce.onion_signs.is_empty() {
+        warn!("Invalid onion signatures from {}", self.address);
+        let message = "Invalid onion signatures".to_string();
+        return self.handle_error(req.req_id, message).await;
       }
+      verified
+    };
+    // Onions cannot be verified without Tor support
+    #[cfg(not(feature = "tor"))]
+    let onions_verified = false;
 
-      let peer = shared_state
-        .peer_db
-        .get_peer(&address)
-        .expect("Could not get peer");
-      let date_added = match peer {
-        Some(peer) => peer.date_added,
-        None => SystemTime::now(),
-      };
-      let peer = Peer {
-        address,
-        last_seen: SystemTime::now(),
-        date_added,
-      };
+    let address = self.address.with_port(announce.port as u16);
+    let reachable = match &self.config.reachability {
+      Some(checker)
+        if adds_own_address(&announce, &address) && self.config.address_filter.accepts(&address) =>
+      {
+        self.port_opened != Some(false) && checker.is_reachable(&address).await
+      }
+      _ => true,
+    };
 
-      let hashes: Vec<Hash> = announce
+    // Denied and unlisted hashes keep their position, since onions and
+    // the returned peers are matched to hashes by index.
+    let (mut denied, mut unlisted) = (0, 0);
+    let hashes: Vec<Option<Hash>> = {
+      let denylist = self.config.denylist.read().unwrap();
+      announce
         .hashes
         .iter()
         .map(|buf| Hash(buf.clone().into_vec()))
-        .collect();
-
-      if announce.onions.is_empty() {
-        let peer_address = peer.address.to_string();
-
-        if !peer_address.starts_with("127.0.0.1") && !peer_address.starts_with("192.") {
-          trace!("Updating peer {}", peer_address);
-          let peer_already_known = shared_state
-            .peer_db
-            .update_peer(&peer, &hashes)
-            .expect("Could not update peer");
-          match peer_already_known {
-            true => info!("Updated peer {} for {} hashes", peer_address, hashes.len()),
-            false => info!("Added peer {} for {} hashes", peer_address, hashes.len()),
+        .map(|hash| {
+          if denylist.denies_hash(&hash) {
+            denied += 1;
+            None
+          } else if !self.config.allowlist.permits(&hash) {
+            unlisted += 1;
+            None
+          } else {
+            Some(hash)
           }
+        })
+        .collect()
+    };
+    if denied > 0 {
+      info!("Ignoring {} denied hashes from {}", denied, self.address);
+      #[cfg(feature = "metrics")]
+      metrics::DENIED_HASHES.inc_by(denied as u64);
+    }
+    if unlisted > 0 {
+      debug!("Ignoring {} unlisted hashes from {}", unlisted, self.address);
+      #[cfg(feature = "metrics")]
+      metrics::UNLISTED_HASHES.inc_by(unlisted as u64);
+    }
+    // Counted against what is stored for the address, so the limit holds
+    // across connections and only grows once an announce is stored.
+    let announce = Arc::new(announce);
+    let hashes = Arc::new(hashes);
+    if let Some(max_peer_hashes) = self.config.max_peer_hashes {
+      let (peer_address, announce, hashes) = (address.clone(), announce.clone(), hashes.clone());
+      let count = shared_state::blocking(&self.shared_state, move |shared_state| {
+        let shared_state = shared_state::read(shared_state);
+        peer_hash_count(&shared_state, &peer_address, &announce, &hashes)
+      });
+      match count.await {
+        Ok(count) if count > max_peer_hashes => {
+          let message = format!("Too many hashes, at most {} per peer", max_peer_hashes);
+          warn!("Rejecting announce from {}: {}", self.address, message);
+          #[cfg(feature = "metrics")]
+          metrics::REJECTED_ANNOUNCES.inc();
+          return self.handle_error(req.req_id, message).await;
         }
-      } else {
-        let mut onion_hashes = HashMap::<String, Vec<Hash>>::new();
-        announce
-          .onions
-          .iter()
-          .zip(hashes.iter())
-          .for_each(|(onion, hash)| {
-            if let Some(hashes) = onion_hashes.get_mut(onion) {
-              hashes.push(hash.clone());
-            } else {
-              onion_hashes.insert(onion.to_string(), vec![hash.clone()]);
-            }
-          });
-        onion_hashes.into_iter().for_each(|(onion, hashes)| {
-          match Address::parse(format!("{}.onion:{}", onion, announce.port)) {
-            Ok(onion) => {
-              let peer = Peer {
-                address: onion,
-                last_seen: SystemTime::now(),
-                date_added,
-              };
-              let num_of_hashes = hashes.len();
-              let t = SystemTime::now();
-              shared_state
-                .peer_db
-                .update_peer(&peer, &hashes)
-                .expect("Could not update peer");
-              trace!(
-                "Updated onion with {} hashes in {:?}",
-                num_of_hashes,
-                SystemTime::now().duration_since(t).unwrap(),
-              );
-            }
-            Err(_) => {}
-          };
-        });
-        info!("Added onions for {} hashes", announce.onions.len());
+        Ok(_) => {}
+        Err(err) => return self.handle_database_error(req.req_id, err).await,
       }
+    }
+    // The peer database locks internally, other connections only wait
+    // for this one where they work on the same peers or hashes.
+    let peers = {
+      let (config, announce) = (self.config.clone(), announce.clone());
+      shared_state::blocking(&self.shared_state, move |shared_state| {
+        let shared_state = shared_state::read(shared_state);
+        let stored_hashes: Vec<Hash> = hashes.iter().flatten().cloned().collect();
+        store_announce(
+          &shared_state,
+          &config,
+          &announce,
+          &hashes,
+          &address,
+          reachable,
+          onions_verified,
+        )
+        .and_then(|()| shared_state.enforce_budget(&stored_hashes))
+        .and_then(|_| find_peers(&shared_state, config.max_peers, &announce, &hashes))
+      })
+    };
+    let mut body = AnnounceResponse::default();
+    body.response.peers = match peers.await {
+      Ok(peers) => peers,
+      Err(err) => return self.handle_database_error(req.req_id, err).await,
+    };
+    self.state = next_state;
 
-      let mut hash_peers = Vec::new();
-      hashes.into_iter().for_each(|hash| {
-        let mut peers = templates::AnnouncePeers::default();
-        shared_state
-          .peer_db
-          .get_peers_for_hash(&hash)
-          .expect("Could not get peers for hash")
-          .into_iter()
-          .for_each(|peer| {
-            let bytes = ByteBuf::from(peer.address.pack());
-            match peer.address {
-              Address::IPV4(_, _) => {
-                // Need to check 'ip4' for backwards compat
-                if announce.need_types.contains(&"ipv4".to_string()) || announce.need_types.contains(&"ip4".to_string()) {
-                  peers.ip_v4.push(bytes);
-                }
-              }
-              Address::IPV6(_, _) => {
-                if announce.need_types.contains(&"ipv6".to_string()) {
-                  peers.ip_v6.push(bytes);
-                }
-              }
-              #[cfg(feature = "tor")]
-              Address::OnionV2(_, _) | Address::OnionV3(_, _) => {
-                if announce.need_types.contains(&"onion".to_string()) {
-                  peers.onion_v2.push(bytes);
-                }
-              }
-              #[cfg(feature = "i2p")]
-              _ => {
-                // TODO: implement i2p
-                unimplemented!()
-              }
-            }
-          });
-        hash_peers.push(peers);
-      });
-      body.peers = hash_peers;
+    #[cfg(feature = "tor")]
+    if !announce.onions.is_empty() && !onions_verified {
+      let onion_sign_this = onion::new_challenge();
+      self.onion_sign_this = Some(onion_sign_this.clone());
+      body.onion_sign_this = Some(onion_sign_this);
     }
     trace!("Response: {:?}", &body);
 
-    let response = self.connection.respond(req.req_id, body);
-    let result = block_on(response);
+    let result = self.respond(req.req_id, body).await;
     if let Err(err) = result {
       error!("Encountered error responding to announce: {:?}", err);
     }
   }
 
-  fn handle_invalid(&mut self, req_id: usize, err: Error) {
+  /// Checks the announce against the hash limit and the lengths of its
+  /// fields.
+  fn check_announce(&self, announce: &templates::Announce) -> Result<(), String> {
+    if let Some(max_hashes) = self.config.max_hashes {
+      if announce.hashes.len() > max_hashes {
+        return Err(format!("Too many hashes, at most {} per announce", max_hashes));
+      }
+    }
+    // Onions are matched to hashes by index
+    if !announce.onions.is_empty() && announce.onions.len() != announce.hashes.len() {
+      return Err(format!(
+        "Announced {} onions for {} hashes",
+        announce.onions.len(),
+        announce.hashes.len()
+      ));
+    }
+    Ok(())
+  }
+
+  /// Checks the `onion_signs` of an announce against the challenge issued
+  /// on this connection. Signatures are expected in the order in which the
+  /// onions first appear in `onions`.
+  #[cfg(feature = "tor")]
+  fn onions_verified(&self, announce: &templates::Announce) -> bool {
+    let onion_sign_this = match &self.onion_sign_this {
+      Some(onion_sign_this) if *onion_sign_this == announce.onion_sign_this => onion_sign_this,
+      _ => return false,
+    };
+
+    let mut onions: Vec<&String> = Vec::new();
+    for onion in announce.onions.iter() {
+      if !onions.contains(&onion) {
+        onions.push(onion);
+      }
+    }
+
+    onions.len() == announce.onion_signs.len()
+      && onions
+        .iter()
+        .zip(announce.onion_signs.iter())
+        .all(|(onion, sign)| onion::verify(onion, onion_sign_this, sign))
+  }
+
+  /// Sends a response, closing the connection if the peer does not take it
+  /// within the write timeout.
+  async fn respond<T: DeserializeOwned + Serialize>(
+    &mut self,
+    req_id: usize,
+    body: T,
+  ) -> Result<(), Error> {
+    let response = within(self.config.write_timeout, self.connection.respond(req_id, body));
+    let result = match response.await {
+      Some(result) => result,
+      None => {
+        warn!("Response to {} not received in time", self.address);
+        #[cfg(feature = "metrics")]
+        metrics::TIMED_OUT_CONNECTIONS.inc();
+        Err(Error::Io(io::ErrorKind::TimedOut.into()))
+      }
+    };
+    if result.is_err() {
+      self.connection.close();
+    }
+    result
+  }
+
+  async fn handle_error(&mut self, req_id: usize, message: String) {
+    let body = templates::Error { error: message };
+    let result = self.respond(req_id, body).await;
+    if let Err(err) = result {
+      error!("Encountered error returning error: {:?}", err);
+    }
+  }
+
+  async fn handle_database_error(&mut self, req_id: usize, err: PeerDBError) {
+    error!("Database error on announce from {}: {:?}", self.address, err);
+    #[cfg(feature = "metrics")]
+    metrics::DATABASE_ERRORS.inc();
+    let message = "Internal database error".to_string();
+    self.handle_error(req_id, message).await
+  }
+
+  async fn handle_invalid(&mut self, req_id: usize, err: Error) {
     error!("Handling invalid request: {:?}", err);
-    let body = templates::Error {
-      error: format!("Invalid data: {:?}", err),
+    self
+      .handle_error(req_id, format!("Invalid data: {:?}", err))
+      .await
+  }
+
+  async fn handle_ping(&mut self, req: Request) {
+    let body = templates::PingResponse {
+      body: "Pong!".to_string(),
     };
-    let response = self.connection.respond(req_id, body);
-    let result = block_on(response);
+    let result = self.respond(req.req_id, body).await;
     if let Err(err) = result {
-      error!("Encountered error returning invalid data error: {:?}", err);
+      error!("Encountered error responding to ping: {:?}", err);
     }
   }
 
-  fn handle_unsupported(&mut self, req_id: usize) {
+  async fn handle_unsupported(&mut self, req_id: usize) {
     let body = "Unknown request".to_string();
-    let response = self.connection.respond(req_id, body);
-    let result = block_on(response);
+    let result = self.respond(req_id, body).await;
     if let Err(err) = result {
       error!(
         "Encountered error responding to unsupported request: {:?}",
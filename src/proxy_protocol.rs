@@ -0,0 +1,99 @@
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Signature that starts every version 2 header.
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// Maximum length of a version 1 header, including the CRLF.
+const V1_MAX_LENGTH: usize = 107;
+const V2_COMMAND_LOCAL: u8 = 0x20;
+const V2_COMMAND_PROXY: u8 = 0x21;
+const V2_TCP_IPV4: u8 = 0x11;
+const V2_TCP_IPV6: u8 = 0x21;
+
+fn invalid(message: &str) -> io::Error {
+  io::Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+/// Reads a PROXY protocol header of version 1 or 2, without reading past
+/// it. Returns the address of the client, or `None` if the header does not
+/// carry one, such as health checks of the proxy itself.
+pub async fn read_header<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<SocketAddr>> {
+  // Both versions are at least as long as the version 2 signature
+  let mut start = [0; 12];
+  reader.read_exact(&mut start).await?;
+  if start == V2_SIGNATURE {
+    read_v2(reader).await
+  } else if start.starts_with(b"PROXY ") {
+    read_v1(reader, &start).await
+  } else {
+    Err(invalid("Missing PROXY protocol header"))
+  }
+}
+
+async fn read_v1<R: AsyncRead + Unpin>(
+  reader: &mut R,
+  start: &[u8],
+) -> io::Result<Option<SocketAddr>> {
+  let mut line = start.to_vec();
+  while !line.ends_with(b"\r\n") {
+    if line.len() >= V1_MAX_LENGTH {
+      return Err(invalid("PROXY protocol header too long"));
+    }
+    line.push(reader.read_u8().await?);
+  }
+
+  let line = std::str::from_utf8(&line[..line.len() - 2])
+    .map_err(|_| invalid("PROXY protocol header is not ASCII"))?;
+  let parts: Vec<&str> = line.split(' ').collect();
+  match parts[..] {
+    ["PROXY", "UNKNOWN", ..] => Ok(None),
+    ["PROXY", protocol @ ("TCP4" | "TCP6"), source, _, source_port, _] => {
+      let ip: IpAddr = source
+        .parse()
+        .map_err(|_| invalid("Invalid source address"))?;
+      if ip.is_ipv4() != (protocol == "TCP4") {
+        return Err(invalid("Source address does not match protocol"));
+      }
+      let port = source_port
+        .parse()
+        .map_err(|_| invalid("Invalid source port"))?;
+      Ok(Some(SocketAddr::new(ip, port)))
+    }
+    _ => Err(invalid("Malformed PROXY protocol header")),
+  }
+}
+
+async fn read_v2<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<SocketAddr>> {
+  let command = reader.read_u8().await?;
+  let family = reader.read_u8().await?;
+  let length = reader.read_u16().await?;
+  let mut addresses = vec![0; length as usize];
+  reader.read_exact(&mut addresses).await?;
+
+  match (command, family) {
+    (V2_COMMAND_LOCAL, _) => Ok(None),
+    (V2_COMMAND_PROXY, V2_TCP_IPV4) => {
+      if addresses.len() < 12 {
+        return Err(invalid("Truncated PROXY protocol addresses"));
+      }
+      let mut ip = [0; 4];
+      ip.copy_from_slice(&addresses[..4]);
+      let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+      Ok(Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port)))
+    }
+    (V2_COMMAND_PROXY, V2_TCP_IPV6) => {
+      if addresses.len() < 36 {
+        return Err(invalid("Truncated PROXY protocol addresses"));
+      }
+      let mut ip = [0; 16];
+      ip.copy_from_slice(&addresses[..16]);
+      let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+      Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port)))
+    }
+    // Other transports carry no address a peer could be reached at
+    (V2_COMMAND_PROXY, _) => Ok(None),
+    _ => Err(invalid("Unsupported PROXY protocol version or command")),
+  }
+}
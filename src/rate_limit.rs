@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Number of tracked IPs after which idle buckets get dropped.
+const PRUNE_THRESHOLD: usize = 1024;
+
+struct TokenBucket {
+  tokens:  f64,
+  updated: Instant,
+}
+
+/// Limits concurrent connections and request rates per IP.
+pub struct RateLimiter {
+  max_connections: usize,
+  rate:            f64,
+  burst:           f64,
+  connections:     Arc<Mutex<HashMap<IpAddr, usize>>>,
+  buckets:         Mutex<HashMap<IpAddr, TokenBucket>>,
+}
+
+/// Counts as an open connection for its IP until dropped.
+pub struct ConnectionGuard {
+  ip:          IpAddr,
+  connections: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl Drop for ConnectionGuard {
+  fn drop(&mut self) {
+    let mut connections = self.connections.lock().unwrap();
+    if let Some(count) = connections.get_mut(&self.ip) {
+      *count -= 1;
+      if *count == 0 {
+        connections.remove(&self.ip);
+      }
+    }
+  }
+}
+
+impl RateLimiter {
+  /// A `max_connections` or `rate` of zero disables the respective limit.
+  pub fn new(max_connections: usize, rate: u16, burst: u16) -> RateLimiter {
+    RateLimiter {
+      max_connections,
+      rate: rate as f64,
+      burst: burst.max(1) as f64,
+      connections: Arc::new(Mutex::new(HashMap::new())),
+      buckets: Mutex::new(HashMap::new()),
+    }
+  }
+
+  /// Registers a connection, returning `None` if the IP
+  /// already has the maximum number of connections open.
+  pub fn acquire_connection(&self, ip: IpAddr) -> Option<ConnectionGuard> {
+    let mut connections = self.connections.lock().unwrap();
+    let count = connections.entry(ip).or_insert(0);
+    if self.max_connections > 0 && *count >= self.max_connections {
+      return None;
+    }
+    *count += 1;
+
+    Some(ConnectionGuard {
+      ip,
+      connections: self.connections.clone(),
+    })
+  }
+
+  /// Takes a token from the IP's bucket, returning false if it is empty.
+  pub fn allow_request(&self, ip: IpAddr) -> bool {
+    if self.rate == 0. {
+      return true;
+    }
+
+    let now = Instant::now();
+    let mut buckets = self.buckets.lock().unwrap();
+    if !buckets.contains_key(&ip) && buckets.len() >= PRUNE_THRESHOLD {
+      // Buckets that have refilled completely are equivalent to new ones
+      let (rate, burst) = (self.rate, self.burst);
+      buckets.retain(|_, bucket| {
+        bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate < burst
+      });
+    }
+
+    let bucket = buckets.entry(ip).or_insert(TokenBucket {
+      tokens:  self.burst,
+      updated: now,
+    });
+    let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+    bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+    bucket.updated = now;
+
+    if bucket.tokens >= 1. {
+      bucket.tokens -= 1.;
+      true
+    } else {
+      false
+    }
+  }
+}
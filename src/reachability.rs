@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::*;
+use serde_json::Value;
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use zeronet_protocol::{templates, PeerAddr as Address};
+
+use crate::connection::Connection;
+
+const DIAL_TIMEOUT: Duration = Duration::from_secs(10);
+/// Fields of a handshake response that show a fileserver answered.
+const HANDSHAKE_FIELDS: &[&str] = &["peer_id", "fileserver_port", "time"];
+
+/// Connects back to announced fileservers to make sure they accept
+/// connections before they are handed out to other peers.
+pub struct ReachabilityChecker {
+  cache_duration: Duration,
+  cache:          Mutex<HashMap<Address, (bool, Instant)>>,
+}
+
+impl ReachabilityChecker {
+  pub fn new(cache_duration: Duration) -> ReachabilityChecker {
+    ReachabilityChecker {
+      cache_duration,
+      cache: Mutex::new(HashMap::new()),
+    }
+  }
+
+  /// Returns whether a ZeroNet handshake with the address succeeds,
+  /// reusing earlier results until they expire.
+  pub async fn is_reachable(&self, address: &Address) -> bool {
+    if let Some(reachable) = self.cached(address) {
+      return reachable;
+    }
+
+    let reachable = match dial(address).await {
+      Ok(()) => true,
+      Err(err) => {
+        debug!("Could not reach {}: {}", address, err);
+        false
+      }
+    };
+
+    let now = Instant::now();
+    let mut cache = self.cache.lock().unwrap();
+    cache.retain(|_, (_, checked)| now.duration_since(*checked) < self.cache_duration);
+    cache.insert(address.clone(), (reachable, now));
+    reachable
+  }
+
+  fn cached(&self, address: &Address) -> Option<bool> {
+    let cache = self.cache.lock().unwrap();
+    match cache.get(address) {
+      Some((reachable, checked)) if checked.elapsed() < self.cache_duration => Some(*reachable),
+      _ => None,
+    }
+  }
+}
+
+async fn dial(address: &Address) -> Result<(), String> {
+  let socket_address: SocketAddr = address.try_into().map_err(|err| format!("{:?}", err))?;
+  let stream = timeout(DIAL_TIMEOUT, TcpStream::connect(socket_address))
+    .await
+    .map_err(|_| "connection timed out".to_string())?
+    .map_err(|err| err.to_string())?;
+
+  let mut connection = Connection::new(stream);
+
+  let mut body = templates::Handshake::new();
+  // Like ZeroNet, only the IP, the port is in `fileserver_port`
+  body.target_address = Some(socket_address.ip().to_string());
+  let response = timeout(DIAL_TIMEOUT, connection.request("handshake", 0, body))
+    .await
+    .map_err(|_| "handshake timed out".to_string())?
+    .map_err(|err| err.to_string())?;
+  trace!("Dial-back handshake response from {}: {:?}", address, response);
+  // A handshake template deserializes from any map, even an error, so
+  // check for the fields every ZeroNet handshake carries
+  let body: Value = response
+    .body()
+    .map_err(|err| format!("invalid handshake response: {:?}", err))?;
+  let is_handshake = body.get("error").is_none()
+    && HANDSHAKE_FIELDS.iter().all(|field| body.get(field).is_some());
+  match is_handshake {
+    true => Ok(()),
+    false => Err(format!("invalid handshake response: {}", body)),
+  }
+}
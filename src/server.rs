@@ -1,28 +1,61 @@
-use std::sync::{Arc, Mutex};
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
 
 use clap::crate_version;
 use log::*;
 use maud::{html, Markup, PreEscaped};
 #[cfg(feature = "metrics")]
 use prometheus::{Encoder, TextEncoder};
-#[cfg(feature = "metrics")]
+use rocket::http::uri::Origin;
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
 use rocket::response::content;
-use rocket::{get, routes, Config, State};
+use rocket::{get, routes, Config, Outcome, State};
 use rocket_contrib::json::Json;
 use serde::Serialize;
-use zeronet_peerdb::get_peer_db_type;
 
+use crate::bittorrent::{self, TrackerConfig};
+use crate::http_tracker::{self, Bencode};
 #[cfg(feature = "metrics")]
 use crate::metrics;
-use crate::shared_state::SharedState;
+use crate::peer_db;
+use crate::shared_state::{self, SharedState};
+use crate::shutdown::Shutdown;
 
 struct StateWrapper {
-  shared_state: Arc<Mutex<SharedState>>,
+  shared_state: Arc<RwLock<SharedState>>,
+  tracker:      TrackerConfig,
+  shutdown:     Shutdown,
 }
 
-pub fn run(shared_state: Arc<Mutex<SharedState>>, port: u16) {
+/// Request guard that turns requests away with 503 once the tracker is
+/// shutting down. Rocket 0.4 cannot be stopped, so the server keeps
+/// accepting requests until the process exits.
+struct Running;
+
+impl<'a, 'r> FromRequest<'a, 'r> for Running {
+  type Error = ();
+
+  fn from_request(request: &'a Request<'r>) -> request::Outcome<Running, ()> {
+    match request.guard::<State<StateWrapper>>() {
+      Outcome::Success(state) if !state.shutdown.is_triggered() => Outcome::Success(Running),
+      _ => Outcome::Failure((Status::ServiceUnavailable, ())),
+    }
+  }
+}
+
+pub fn run(
+  shared_state: Arc<RwLock<SharedState>>,
+  port: u16,
+  tracker: TrackerConfig,
+  shutdown: Shutdown,
+) {
   info!("Starting server at localhost:{}", port);
-  let state = StateWrapper { shared_state };
+  let state = StateWrapper {
+    shared_state,
+    tracker,
+    shutdown,
+  };
   let mut config = Config::active().unwrap();
   config.set_port(port);
 
@@ -34,19 +67,20 @@ pub fn run(shared_state: Arc<Mutex<SharedState>>, port: u16) {
   rocket::custom(config)
     .mount("/", routes![overview, peers, hashes, hash_stats])
     .mount("/", stats_routes)
+    .mount("/", routes![bittorrent_announce, bittorrent_scrape])
     .manage(state)
     .launch();
 }
 
 #[get("/")]
-fn overview(state: State<StateWrapper>) -> Markup {
-  let shared_state = state.shared_state.lock().unwrap();
+fn overview(state: State<StateWrapper>, _running: Running) -> Markup {
+  let shared_state = shared_state::read(&state.shared_state);
   let uptime = shared_state.start_time.elapsed().unwrap().as_secs_f64() / 60f64 / 60f64;
 
   html! {
     h1 { "ZeroNet Tracker" }
     p { "Version: v" (crate_version!()) }
-    p { "PeerDB: " (get_peer_db_type()) }
+    p { "PeerDB: " (shared_state.peer_db.name()) }
     p { "Uptime: " (format!("{:.2}", uptime)) "h" }
     p {
       a href="/peers" { "Peers: " (shared_state.peer_db.get_peer_count().unwrap_or(0)) }
@@ -85,8 +119,8 @@ li {
 </style>"#;
 
 #[get("/peers")]
-fn peers(state: State<StateWrapper>) -> Markup {
-  let shared_state = state.shared_state.lock().unwrap();
+fn peers(state: State<StateWrapper>, _running: Running) -> Markup {
+  let shared_state = shared_state::read(&state.shared_state);
   let peers = shared_state
     .peer_db
     .get_peers()
@@ -105,8 +139,8 @@ fn peers(state: State<StateWrapper>) -> Markup {
 }
 
 #[get("/hashes")]
-fn hashes(state: State<StateWrapper>) -> Markup {
-  let shared_state = state.shared_state.lock().unwrap();
+fn hashes(state: State<StateWrapper>, _running: Running) -> Markup {
+  let shared_state = shared_state::read(&state.shared_state);
   let hashes = shared_state
     .peer_db
     .get_hashes()
@@ -138,8 +172,8 @@ struct Stats {
 
 #[cfg(feature = "metrics")]
 #[get("/stats/json")]
-fn stats_json(state: State<StateWrapper>) -> Json<Stats> {
-  let shared_state = state.shared_state.lock().unwrap();
+fn stats_json(state: State<StateWrapper>, _running: Running) -> Json<Stats> {
+  let shared_state = shared_state::read(&state.shared_state);
 
   Json(Stats {
     opened_connections: metrics::OPENED_CONNECTIONS.get() as usize,
@@ -153,7 +187,7 @@ fn stats_json(state: State<StateWrapper>) -> Json<Stats> {
 
 #[cfg(feature = "metrics")]
 #[get("/metrics")]
-fn stats_prometheus(state: State<StateWrapper>) -> content::Plain<Vec<u8>> {
+fn stats_prometheus(state: State<StateWrapper>, _running: Running) -> content::Plain<Vec<u8>> {
   metrics::update_metrics(&state.shared_state);
 
   let encoder = TextEncoder::new();
@@ -171,8 +205,8 @@ struct HashStat {
 }
 
 #[get("/stats/hashes")]
-fn hash_stats(state: State<StateWrapper>) -> Json<Vec<HashStat>> {
-  let shared_state = state.shared_state.lock().unwrap();
+fn hash_stats(state: State<StateWrapper>, _running: Running) -> Json<Vec<HashStat>> {
+  let shared_state = shared_state::read(&state.shared_state);
   let hashes = shared_state
     .peer_db
     .get_hashes()
@@ -186,3 +220,58 @@ fn hash_stats(state: State<StateWrapper>) -> Json<Vec<HashStat>> {
     .collect();
   Json(hashes)
 }
+
+fn bencoded(value: Bencode) -> content::Plain<Vec<u8>> {
+  content::Plain(value.encode())
+}
+
+fn database_error(remote: SocketAddr, err: peer_db::Error) -> content::Plain<Vec<u8>> {
+  error!("Database error on BitTorrent request from {}: {:?}", remote, err);
+  #[cfg(feature = "metrics")]
+  metrics::DATABASE_ERRORS.inc();
+  bencoded(http_tracker::failure_response("Internal database error"))
+}
+
+/// BitTorrent announce (BEP 3). The query is parsed by hand because info
+/// hashes and peer ids are percent-encoded binary, not UTF-8.
+#[get("/announce")]
+fn bittorrent_announce(
+  state: State<StateWrapper>,
+  _running: Running,
+  origin: &Origin,
+  remote: SocketAddr,
+) -> content::Plain<Vec<u8>> {
+  let (request, compact) = match http_tracker::parse_announce(origin.query().unwrap_or("")) {
+    Ok(announce) => announce,
+    Err(reason) => return bencoded(http_tracker::failure_response(&reason)),
+  };
+  trace!("BitTorrent announce from {}: {:?}", remote, request);
+
+  match bittorrent::announce(&state.shared_state, &state.tracker, remote.ip(), &request) {
+    Ok(swarm) => bencoded(http_tracker::announce_response(
+      &state.tracker,
+      &swarm,
+      compact,
+    )),
+    Err(err) => database_error(remote, err),
+  }
+}
+
+#[get("/scrape")]
+fn bittorrent_scrape(
+  state: State<StateWrapper>,
+  _running: Running,
+  origin: &Origin,
+  remote: SocketAddr,
+) -> content::Plain<Vec<u8>> {
+  let params = http_tracker::parse_query(origin.query().unwrap_or(""));
+  let hashes = http_tracker::info_hashes(&params);
+  if hashes.is_empty() {
+    return bencoded(http_tracker::failure_response("Missing or invalid info_hash"));
+  }
+
+  match bittorrent::scrape(&state.shared_state, &state.tracker, remote.ip(), &hashes) {
+    Ok(counts) => bencoded(http_tracker::scrape_response(&hashes, &counts)),
+    Err(err) => database_error(remote, err),
+  }
+}
@@ -0,0 +1,323 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::Hasher;
+use std::sync::{PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::SystemTime;
+
+use zeronet_peerdb::{Hash, Peer};
+use zeronet_protocol::PeerAddr as Address;
+
+use crate::peer_db::{Error, PeerStore};
+
+/// Number of locks the peers and the hashes are each spread over.
+const SHARDS: usize = 64;
+
+fn shard<K: std::hash::Hash>(key: &K) -> usize {
+  let mut hasher = DefaultHasher::new();
+  key.hash(&mut hasher);
+  hasher.finish() as usize % SHARDS
+}
+
+// The maps are left consistent after every single change, so a panic of
+// another thread holding a lock does not have to take the tracker down.
+fn read<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+  lock.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn write<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+  lock.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+struct StoredPeer {
+  peer:   Peer,
+  hashes: HashSet<Hash>,
+}
+
+/// Keeps peers in memory, behind locks sharded by address and by hash.
+///
+/// Changes to a peer hold the lock of its shard while they link or unlink
+/// its hashes one shard at a time, so lookups only ever wait for the
+/// shards they read. Locks are always taken peer shard first, and lookups
+/// never hold a hash shard while reading peers.
+pub struct ShardedPeerDB {
+  /// Peers along with the hashes they announced
+  peers:  Vec<RwLock<HashMap<Address, StoredPeer>>>,
+  /// Addresses of the peers of each hash, hashes without peers are removed
+  hashes: Vec<RwLock<HashMap<Hash, HashSet<Address>>>>,
+}
+
+impl Default for ShardedPeerDB {
+  fn default() -> ShardedPeerDB {
+    ShardedPeerDB::new()
+  }
+}
+
+impl ShardedPeerDB {
+  pub fn new() -> ShardedPeerDB {
+    ShardedPeerDB {
+      peers:  (0..SHARDS).map(|_| RwLock::default()).collect(),
+      hashes: (0..SHARDS).map(|_| RwLock::default()).collect(),
+    }
+  }
+
+  fn link(&self, address: &Address, hash: &Hash) {
+    write(&self.hashes[shard(hash)])
+      .entry(hash.clone())
+      .or_default()
+      .insert(address.clone());
+  }
+
+  fn unlink(&self, address: &Address, hash: &Hash) {
+    let mut hashes = write(&self.hashes[shard(hash)]);
+    if let Some(peers) = hashes.get_mut(hash) {
+      peers.remove(address);
+      if peers.is_empty() {
+        hashes.remove(hash);
+      }
+    }
+  }
+
+  /// Adds or updates a peer in its locked shard and links its new hashes.
+  /// Returns true if the peer was known.
+  fn store(&self, peers: &mut HashMap<Address, StoredPeer>, peer: &Peer, hashes: &[Hash]) -> bool {
+    let known = peers.contains_key(&peer.address);
+    let stored = peers
+      .entry(peer.address.clone())
+      .or_insert_with(|| StoredPeer {
+        peer:   peer.clone(),
+        hashes: HashSet::new(),
+      });
+    stored.peer.last_seen = peer.last_seen;
+    for hash in hashes {
+      if stored.hashes.insert(hash.clone()) {
+        self.link(&peer.address, hash);
+      }
+    }
+    known
+  }
+
+  /// Looks up the peers at the given addresses, reading every peer shard
+  /// at most once. Peers removed in the meantime are left out.
+  fn resolve(&self, addresses: Vec<Vec<Address>>) -> Vec<Vec<Peer>> {
+    let mut by_shard: Vec<Vec<&Address>> = vec![Vec::new(); SHARDS];
+    for address in addresses.iter().flatten() {
+      by_shard[shard(address)].push(address);
+    }
+    let mut found: HashMap<&Address, Peer> = HashMap::new();
+    for (shard, shard_addresses) in by_shard.into_iter().enumerate() {
+      if shard_addresses.is_empty() {
+        continue;
+      }
+      let peers = read(&self.peers[shard]);
+      for address in shard_addresses {
+        if let Some(stored) = peers.get(address) {
+          found.insert(address, stored.peer.clone());
+        }
+      }
+    }
+    addresses
+      .iter()
+      .map(|addresses| {
+        addresses
+          .iter()
+          .filter_map(|address| found.get(address).cloned())
+          .collect()
+      })
+      .collect()
+  }
+
+  fn addresses_for_hash(&self, hash: &Hash) -> Vec<Address> {
+    match read(&self.hashes[shard(hash)]).get(hash) {
+      Some(peers) => peers.iter().cloned().collect(),
+      None => Vec::new(),
+    }
+  }
+}
+
+impl PeerStore for ShardedPeerDB {
+  fn name(&self) -> &'static str {
+    "In-memory"
+  }
+
+  fn update_peer(&self, peer: &Peer, hashes: &[Hash]) -> Result<bool, Error> {
+    let mut peers = write(&self.peers[shard(&peer.address)]);
+    Ok(self.store(&mut peers, peer, hashes))
+  }
+
+  fn update_peers(&self, updates: &[(Peer, Vec<Hash>)]) -> Result<Vec<bool>, Error> {
+    // Updates ordered by shard, so each shard is locked once
+    let mut order: Vec<(usize, usize)> = updates
+      .iter()
+      .enumerate()
+      .map(|(i, (peer, _))| (shard(&peer.address), i))
+      .collect();
+    order.sort_unstable();
+    let mut known = vec![false; updates.len()];
+    for shard_updates in order.chunk_by(|a, b| a.0 == b.0) {
+      let mut peers = write(&self.peers[shard_updates[0].0]);
+      for (_, i) in shard_updates {
+        let (peer, hashes) = &updates[*i];
+        known[*i] = self.store(&mut peers, peer, hashes);
+      }
+    }
+    Ok(known)
+  }
+
+  fn replace_peer(&self, peer: &Peer, hashes: &[Hash]) -> Result<bool, Error> {
+    let mut peers = write(&self.peers[shard(&peer.address)]);
+    let hashes: HashSet<Hash> = hashes.iter().cloned().collect();
+    let (known, previous) = match peers.get_mut(&peer.address) {
+      Some(stored) => {
+        stored.peer.last_seen = peer.last_seen;
+        (true, std::mem::replace(&mut stored.hashes, hashes.clone()))
+      }
+      None => {
+        let stored = StoredPeer {
+          peer:   peer.clone(),
+          hashes: hashes.clone(),
+        };
+        peers.insert(peer.address.clone(), stored);
+        (false, HashSet::new())
+      }
+    };
+    for hash in hashes.difference(&previous) {
+      self.link(&peer.address, hash);
+    }
+    for hash in previous.difference(&hashes) {
+      self.unlink(&peer.address, hash);
+    }
+    Ok(known)
+  }
+
+  fn remove_peer(&self, address: &Address) -> Result<Option<Peer>, Error> {
+    let mut peers = write(&self.peers[shard(address)]);
+    let stored = match peers.remove(address) {
+      Some(stored) => stored,
+      None => return Ok(None),
+    };
+    for hash in stored.hashes.iter() {
+      self.unlink(address, hash);
+    }
+    Ok(Some(stored.peer))
+  }
+
+  fn unlink_peer(&self, hash: &Hash, address: &Address) -> Result<bool, Error> {
+    let mut peers = write(&self.peers[shard(address)]);
+    let stored = match peers.get_mut(address) {
+      Some(stored) => stored,
+      None => return Ok(false),
+    };
+    if !stored.hashes.remove(hash) {
+      return Ok(false);
+    }
+    if stored.hashes.is_empty() {
+      peers.remove(address);
+    }
+    self.unlink(address, hash);
+    Ok(true)
+  }
+
+  fn get_peer(&self, address: &Address) -> Result<Option<Peer>, Error> {
+    let peers = read(&self.peers[shard(address)]);
+    Ok(peers.get(address).map(|stored| stored.peer.clone()))
+  }
+
+  fn get_peers(&self) -> Result<Vec<Peer>, Error> {
+    let mut found = Vec::new();
+    for peers in self.peers.iter() {
+      found.extend(read(peers).values().map(|stored| stored.peer.clone()));
+    }
+    Ok(found)
+  }
+
+  fn get_peers_for_hash(&self, hash: &Hash) -> Result<Vec<Peer>, Error> {
+    let addresses = self.addresses_for_hash(hash);
+    Ok(self.resolve(vec![addresses]).pop().unwrap())
+  }
+
+  fn get_peers_for_hashes(&self, hashes: &[Hash]) -> Result<Vec<Vec<Peer>>, Error> {
+    let mut by_shard: Vec<Vec<&Hash>> = vec![Vec::new(); SHARDS];
+    for hash in hashes {
+      by_shard[shard(hash)].push(hash);
+    }
+    // Index of every distinct hash in `addresses`
+    let mut found: HashMap<&Hash, usize> = HashMap::with_capacity(hashes.len());
+    let mut addresses = Vec::new();
+    for (shard, shard_hashes) in by_shard.into_iter().enumerate() {
+      if shard_hashes.is_empty() {
+        continue;
+      }
+      let stored = read(&self.hashes[shard]);
+      for hash in shard_hashes {
+        if found.contains_key(hash) {
+          continue;
+        }
+        found.insert(hash, addresses.len());
+        addresses.push(match stored.get(hash) {
+          Some(peers) => peers.iter().cloned().collect(),
+          None => Vec::new(),
+        });
+      }
+    }
+    let peers = self.resolve(addresses);
+    Ok(
+      hashes
+        .iter()
+        .map(|hash| peers[found[hash]].clone())
+        .collect(),
+    )
+  }
+
+  fn get_hashes_for_peer(&self, address: &Address) -> Result<Vec<Hash>, Error> {
+    let peers = read(&self.peers[shard(address)]);
+    Ok(match peers.get(address) {
+      Some(stored) => stored.hashes.iter().cloned().collect(),
+      None => Vec::new(),
+    })
+  }
+
+  fn get_hashes(&self) -> Result<Vec<(Hash, usize)>, Error> {
+    let mut found = Vec::new();
+    for hashes in self.hashes.iter() {
+      found.extend(
+        read(hashes)
+          .iter()
+          .map(|(hash, peers)| (hash.clone(), peers.len())),
+      );
+    }
+    Ok(found)
+  }
+
+  fn get_peer_count(&self) -> Result<usize, Error> {
+    Ok(self.peers.iter().map(|peers| read(peers).len()).sum())
+  }
+
+  fn get_hash_count(&self) -> Result<usize, Error> {
+    Ok(self.hashes.iter().map(|hashes| read(hashes).len()).sum())
+  }
+
+  fn cleanup_peers(&self, timestamp: SystemTime) -> Result<usize, Error> {
+    let mut removed = 0;
+    for peers in self.peers.iter() {
+      let mut peers = write(peers);
+      let stale: Vec<Address> = peers
+        .iter()
+        .filter(|(_, stored)| stored.peer.last_seen < timestamp)
+        .map(|(address, _)| address.clone())
+        .collect();
+      for address in stale {
+        let stored = peers.remove(&address).unwrap();
+        for hash in stored.hashes.iter() {
+          self.unlink(&address, hash);
+        }
+        removed += 1;
+      }
+    }
+    Ok(removed)
+  }
+
+  fn cleanup_hashes(&self) -> Result<usize, Error> {
+    // Hashes are already removed along with their last peer
+    Ok(0)
+  }
+}
@@ -1,23 +1,97 @@
+use std::panic;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use std::time::SystemTime;
 
-use zeronet_peerdb::{Error, PeerDatabase, PeerDB};
+use log::*;
+use tokio::task;
+use zeronet_peerdb::Hash;
 
 use crate::args::Args;
+use crate::budget::Budget;
+use crate::peer_db::{Error, PeerStore};
+use crate::sharded_peer_db::ShardedPeerDB;
+#[cfg(feature = "sql")]
+use crate::sqlite_peer_db::SqlitePeerDB;
 
 pub struct SharedState {
-  pub peer_db:    Box<dyn PeerDatabase<Error = Error> + Send>,
+  /// Locks internally, so connections only need to read the shared state
+  pub peer_db:    Box<dyn PeerStore>,
   pub start_time: SystemTime,
+  pub budget:     Budget,
 }
 
 impl SharedState {
   pub fn new(args: &Args) -> SharedState {
-
     SharedState {
-      #[cfg(feature = "sql")]
-      peer_db:    Box::new(PeerDB::new(args.database_file.clone()).unwrap()),
-      #[cfg(not(feature = "sql"))]
-      peer_db:    Box::new(PeerDB::new().unwrap()),
+      peer_db:    open_peer_db(args),
       start_time: SystemTime::now(),
+      budget:     Budget::from(args),
     }
   }
+
+  /// Evicts peers to bring the just announced `hashes` and the database as
+  /// a whole back within the budget.
+  pub fn enforce_budget(&self, hashes: &[Hash]) -> Result<usize, Error> {
+    self.budget.enforce(self.peer_db.as_ref(), hashes)
+  }
+}
+
+/// Opens the SQLite database if one is configured, peers are kept in
+/// memory otherwise.
+#[cfg_attr(not(feature = "sql"), allow(unused_variables))]
+fn open_peer_db(args: &Args) -> Box<dyn PeerStore> {
+  #[cfg(feature = "sql")]
+  if let Some(path) = &args.database_file {
+    return Box::new(SqlitePeerDB::open(path).unwrap());
+  }
+  Box::new(ShardedPeerDB::new())
+}
+
+/// Runs `f` on a thread of the blocking pool, so peer database calls that
+/// wait for SQLite do not hold up the other tasks of a runtime worker. A
+/// panic of `f` is resumed in the caller.
+pub async fn blocking<T, F>(shared_state: &Arc<RwLock<SharedState>>, f: F) -> T
+where
+  F: FnOnce(&RwLock<SharedState>) -> T + Send + 'static,
+  T: Send + 'static,
+{
+  let shared_state = shared_state.clone();
+  match task::spawn_blocking(move || f(&shared_state)).await {
+    Ok(result) => result,
+    Err(err) => match err.try_into_panic() {
+      Ok(payload) => panic::resume_unwind(payload),
+      Err(err) => panic!("Database task did not finish: {}", err),
+    },
+  }
+}
+
+/// Locks the shared state for reading, lookups of different connections
+/// run in parallel.
+pub fn read(shared_state: &RwLock<SharedState>) -> RwLockReadGuard<'_, SharedState> {
+  match shared_state.read() {
+    Ok(guard) => guard,
+    Err(poisoned) => {
+      recovering();
+      shared_state.clear_poison();
+      poisoned.into_inner()
+    }
+  }
+}
+
+/// Locks the shared state for writing, recovering it if a thread panicked
+/// while writing, so a single failed request does not bring down the tracker.
+#[cfg_attr(not(test), allow(dead_code))]
+pub fn write(shared_state: &RwLock<SharedState>) -> RwLockWriteGuard<'_, SharedState> {
+  match shared_state.write() {
+    Ok(guard) => guard,
+    Err(poisoned) => {
+      recovering();
+      shared_state.clear_poison();
+      poisoned.into_inner()
+    }
+  }
+}
+
+fn recovering() {
+  error!("Recovering shared state after a panic while it was locked");
 }
@@ -0,0 +1,51 @@
+use std::future::pending;
+
+use log::*;
+use tokio::sync::watch;
+
+/// Tells long running tasks that the tracker is shutting down.
+#[derive(Clone)]
+pub struct Shutdown(watch::Receiver<bool>);
+
+/// Creates a shutdown handle together with the sender that triggers it.
+pub fn channel() -> (watch::Sender<bool>, Shutdown) {
+  let (sender, receiver) = watch::channel(false);
+  (sender, Shutdown(receiver))
+}
+
+impl Shutdown {
+  /// Whether shutdown has been triggered.
+  #[cfg_attr(not(feature = "server"), allow(dead_code))]
+  pub fn is_triggered(&self) -> bool {
+    *self.0.borrow()
+  }
+
+  /// Resolves once shutdown has been triggered. Never resolves if the
+  /// sender is dropped without triggering it.
+  pub async fn triggered(&mut self) {
+    while !*self.0.borrow() {
+      if self.0.changed().await.is_err() {
+        pending::<()>().await;
+      }
+    }
+  }
+}
+
+/// Resolves when the process is asked to terminate.
+pub async fn signal() {
+  #[cfg(unix)]
+  {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate()).expect("Could not listen for SIGTERM");
+    tokio::select! {
+      _ = tokio::signal::ctrl_c() => info!("Received SIGINT"),
+      _ = terminate.recv() => info!("Received SIGTERM"),
+    }
+  }
+  #[cfg(not(unix))]
+  {
+    let _ = tokio::signal::ctrl_c().await;
+    info!("Received Ctrl-C");
+  }
+}
@@ -0,0 +1,408 @@
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::*;
+use rusqlite::types::Type;
+use rusqlite::{params, params_from_iter, Connection, OpenFlags, OptionalExtension, Row};
+use zeronet_peerdb::{Hash, Peer};
+use zeronet_protocol::PeerAddr as Address;
+
+use crate::peer_db::{Error, PeerStore};
+
+/// How long a connection waits for a lock held by another process, such
+/// as a backup tool, before giving up.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Read connections kept open between lookups, more are opened while
+/// that many lookups are running and closed again afterwards.
+const MAX_IDLE_READERS: usize = 16;
+/// Hashes looked up per query, well below the limit of SQLite on the
+/// number of parameters.
+const CHUNK_SIZE: usize = 500;
+
+/// Tables as `zeronet_peerdb` creates them, so database files of earlier
+/// versions open unchanged. The index on `peer_hashes (hash_pk)` is added
+/// for lookups by hash.
+const SCHEMA: &str = "
+  CREATE TABLE IF NOT EXISTS peers (
+    pk INTEGER PRIMARY KEY AUTOINCREMENT,
+    address TEXT UNIQUE NOT NULL,
+    date_added TIMESTAMP,
+    last_seen TIMESTAMP
+  );
+  CREATE UNIQUE INDEX IF NOT EXISTS peer_address_idx ON peers (address);
+  CREATE TABLE IF NOT EXISTS hashes (
+    pk INTEGER PRIMARY KEY AUTOINCREMENT,
+    hash BLOB UNIQUE NOT NULL
+  );
+  CREATE UNIQUE INDEX IF NOT EXISTS hash_idx ON hashes (hash);
+  CREATE TABLE IF NOT EXISTS peer_hashes (
+    peer_pk INTEGER REFERENCES peers(pk),
+    hash_pk INTEGER REFERENCES hashes(pk),
+    UNIQUE(peer_pk, hash_pk)
+  );
+  CREATE UNIQUE INDEX IF NOT EXISTS peer_hash_idx ON peer_hashes (peer_pk, hash_pk);
+  CREATE INDEX IF NOT EXISTS peer_hashes_hash_idx ON peer_hashes (hash_pk);
+";
+
+const PEER_COLUMNS: &str = "p.address, p.date_added, p.last_seen";
+
+fn unix_to_timestamp(seconds: i64) -> SystemTime {
+  UNIX_EPOCH + Duration::from_secs(seconds.max(0) as u64)
+}
+
+fn timestamp_to_unix(timestamp: SystemTime) -> i64 {
+  timestamp
+    .duration_since(UNIX_EPOCH)
+    .map_or(0, |duration| duration.as_secs() as i64)
+}
+
+fn peer_from_row(row: &Row) -> rusqlite::Result<Peer> {
+  let address: String = row.get(0)?;
+  Ok(Peer {
+    address:    Address::parse(address)
+      .map_err(|err| rusqlite::Error::FromSqlConversionFailure(0, Type::Text, Box::new(err)))?,
+    date_added: unix_to_timestamp(row.get(1)?),
+    last_seen:  unix_to_timestamp(row.get(2)?),
+  })
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+  mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Keeps peers in an SQLite database file in write-ahead log mode.
+///
+/// Writes take turns on a single connection and commit in one transaction
+/// each. Lookups take a connection from a pool of read-only connections,
+/// so they neither wait for each other nor for a write in progress.
+pub struct SqlitePeerDB {
+  path:    PathBuf,
+  writer:  Mutex<Connection>,
+  /// Idle read connections
+  readers: Mutex<Vec<Connection>>,
+}
+
+/// A read connection that goes back to the pool when dropped.
+struct Reader<'a> {
+  peer_db:    &'a SqlitePeerDB,
+  connection: Option<Connection>,
+}
+
+impl Deref for Reader<'_> {
+  type Target = Connection;
+
+  fn deref(&self) -> &Connection {
+    self.connection.as_ref().unwrap()
+  }
+}
+
+impl DerefMut for Reader<'_> {
+  fn deref_mut(&mut self) -> &mut Connection {
+    self.connection.as_mut().unwrap()
+  }
+}
+
+impl Drop for Reader<'_> {
+  fn drop(&mut self) {
+    let mut readers = lock(&self.peer_db.readers);
+    if readers.len() < MAX_IDLE_READERS {
+      readers.push(self.connection.take().unwrap());
+    }
+  }
+}
+
+impl SqlitePeerDB {
+  /// Opens the database at `path`, creating it and its tables first if
+  /// they do not exist.
+  pub fn open(path: &Path) -> Result<SqlitePeerDB, Error> {
+    let writer = Connection::open(path)?;
+    writer.busy_timeout(BUSY_TIMEOUT)?;
+    writer.execute_batch(SCHEMA)?;
+    // Readers see the last commit while a write is in progress
+    let mode: String =
+      writer.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get(0))?;
+    if !mode.eq_ignore_ascii_case("wal") {
+      warn!(
+        "SQLite database uses journal mode {}, lookups wait for writes",
+        mode
+      );
+    }
+    Ok(SqlitePeerDB {
+      path:    path.to_path_buf(),
+      writer:  Mutex::new(writer),
+      readers: Mutex::new(Vec::new()),
+    })
+  }
+
+  fn writer(&self) -> MutexGuard<'_, Connection> {
+    lock(&self.writer)
+  }
+
+  fn reader(&self) -> Result<Reader<'_>, Error> {
+    let idle = lock(&self.readers).pop();
+    let connection = match idle {
+      Some(connection) => connection,
+      None => {
+        let flags = OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+        let connection = Connection::open_with_flags(&self.path, flags)?;
+        connection.busy_timeout(BUSY_TIMEOUT)?;
+        connection
+      }
+    };
+    Ok(Reader {
+      peer_db:    self,
+      connection: Some(connection),
+    })
+  }
+}
+
+/// Adds or updates a peer and links its hashes, as part of a transaction.
+/// Returns true if the peer was known.
+fn store_peer(connection: &Connection, peer: &Peer, hashes: &[Hash]) -> Result<bool, Error> {
+  let address = peer.address.to_string();
+  let known: Option<i64> = connection
+    .prepare_cached("SELECT pk FROM peers WHERE address = ?")?
+    .query_row(params![address], |row| row.get(0))
+    .optional()?;
+  let peer_pk = match known {
+    Some(peer_pk) => {
+      connection
+        .prepare_cached("UPDATE peers SET last_seen = ? WHERE pk = ?")?
+        .execute(params![timestamp_to_unix(peer.last_seen), peer_pk])?;
+      peer_pk
+    }
+    None => {
+      connection
+        .prepare_cached("INSERT INTO peers (address, date_added, last_seen) VALUES (?, ?, ?)")?
+        .execute(params![
+          address,
+          timestamp_to_unix(peer.date_added),
+          timestamp_to_unix(peer.last_seen)
+        ])?;
+      connection.last_insert_rowid()
+    }
+  };
+
+  let mut insert_hash =
+    connection.prepare_cached("INSERT INTO hashes (hash) VALUES (?) ON CONFLICT DO NOTHING")?;
+  let mut link_hash = connection.prepare_cached(
+    "INSERT INTO peer_hashes (peer_pk, hash_pk)
+    VALUES (?, (SELECT pk FROM hashes WHERE hash = ?))
+    ON CONFLICT DO NOTHING",
+  )?;
+  for hash in hashes {
+    insert_hash.execute(params![hash.0])?;
+    link_hash.execute(params![peer_pk, hash.0])?;
+  }
+  Ok(known.is_some())
+}
+
+/// Removes a peer and its links, as part of a transaction.
+fn delete_peer(connection: &Connection, address: &Address) -> Result<Option<Peer>, Error> {
+  let address = address.to_string();
+  connection
+    .prepare_cached(
+      "DELETE FROM peer_hashes
+      WHERE peer_pk IN (SELECT pk FROM peers WHERE address = ?)",
+    )?
+    .execute(params![address])?;
+  let peer = connection
+    .prepare_cached("DELETE FROM peers WHERE address = ? RETURNING address, date_added, last_seen")?
+    .query_row(params![address], peer_from_row)
+    .optional()?;
+  Ok(peer)
+}
+
+impl PeerStore for SqlitePeerDB {
+  fn name(&self) -> &'static str {
+    "SQLite"
+  }
+
+  fn update_peer(&self, peer: &Peer, hashes: &[Hash]) -> Result<bool, Error> {
+    let mut writer = self.writer();
+    let transaction = writer.transaction()?;
+    let known = store_peer(&transaction, peer, hashes)?;
+    transaction.commit()?;
+    Ok(known)
+  }
+
+  fn replace_peer(&self, peer: &Peer, hashes: &[Hash]) -> Result<bool, Error> {
+    let mut writer = self.writer();
+    // Rolled back when dropped without committing
+    let transaction = writer.transaction()?;
+    let previous = delete_peer(&transaction, &peer.address)?;
+    let peer = Peer {
+      date_added: previous
+        .as_ref()
+        .map_or(peer.date_added, |previous| previous.date_added),
+      ..peer.clone()
+    };
+    store_peer(&transaction, &peer, hashes)?;
+    transaction.commit()?;
+    Ok(previous.is_some())
+  }
+
+  fn update_peers(&self, updates: &[(Peer, Vec<Hash>)]) -> Result<Vec<bool>, Error> {
+    let mut writer = self.writer();
+    let transaction = writer.transaction()?;
+    let known = updates
+      .iter()
+      .map(|(peer, hashes)| store_peer(&transaction, peer, hashes))
+      .collect::<Result<_, _>>()?;
+    transaction.commit()?;
+    Ok(known)
+  }
+
+  fn remove_peer(&self, address: &Address) -> Result<Option<Peer>, Error> {
+    let mut writer = self.writer();
+    let transaction = writer.transaction()?;
+    let peer = delete_peer(&transaction, address)?;
+    transaction.commit()?;
+    Ok(peer)
+  }
+
+  fn unlink_peer(&self, hash: &Hash, address: &Address) -> Result<bool, Error> {
+    let address = address.to_string();
+    let mut writer = self.writer();
+    let transaction = writer.transaction()?;
+    let unlinked = transaction
+      .prepare_cached(
+        "DELETE FROM peer_hashes
+        WHERE peer_pk IN (SELECT pk FROM peers WHERE address = ?)
+        AND hash_pk IN (SELECT pk FROM hashes WHERE hash = ?)",
+      )?
+      .execute(params![address, hash.0])?;
+    if unlinked > 0 {
+      transaction
+        .prepare_cached(
+          "DELETE FROM peers WHERE address = ?
+          AND NOT EXISTS (SELECT 1 FROM peer_hashes WHERE peer_pk = peers.pk)",
+        )?
+        .execute(params![address])?;
+    }
+    transaction.commit()?;
+    Ok(unlinked > 0)
+  }
+
+  fn get_peer(&self, address: &Address) -> Result<Option<Peer>, Error> {
+    let peer = self
+      .reader()?
+      .prepare_cached(&format!(
+        "SELECT {} FROM peers p WHERE address = ?",
+        PEER_COLUMNS
+      ))?
+      .query_row(params![address.to_string()], peer_from_row)
+      .optional()?;
+    Ok(peer)
+  }
+
+  fn get_peers(&self) -> Result<Vec<Peer>, Error> {
+    let reader = self.reader()?;
+    let mut statement = reader.prepare_cached(&format!("SELECT {} FROM peers p", PEER_COLUMNS))?;
+    let peers = statement.query_map([], peer_from_row)?;
+    Ok(peers.collect::<Result<_, _>>()?)
+  }
+
+  fn get_peers_for_hash(&self, hash: &Hash) -> Result<Vec<Peer>, Error> {
+    let reader = self.reader()?;
+    let mut statement = reader.prepare_cached(&format!(
+      "SELECT {} FROM hashes h
+      JOIN peer_hashes ph ON ph.hash_pk = h.pk
+      JOIN peers p ON p.pk = ph.peer_pk
+      WHERE h.hash = ?",
+      PEER_COLUMNS
+    ))?;
+    let peers = statement.query_map(params![hash.0], peer_from_row)?;
+    Ok(peers.collect::<Result<_, _>>()?)
+  }
+
+  fn get_peers_for_hashes(&self, hashes: &[Hash]) -> Result<Vec<Vec<Peer>>, Error> {
+    let mut distinct: Vec<&Hash> = hashes.iter().collect();
+    distinct.sort_unstable();
+    distinct.dedup();
+    let mut found: HashMap<Hash, Vec<Peer>> = HashMap::with_capacity(distinct.len());
+    let mut reader = self.reader()?;
+    // Chunks are looked up in one read transaction, so they agree
+    let transaction = reader.transaction()?;
+    for chunk in distinct.chunks(CHUNK_SIZE) {
+      let mut statement = transaction.prepare(&format!(
+        "SELECT {}, h.hash FROM hashes h
+        JOIN peer_hashes ph ON ph.hash_pk = h.pk
+        JOIN peers p ON p.pk = ph.peer_pk
+        WHERE h.hash IN ({})",
+        PEER_COLUMNS,
+        vec!["?"; chunk.len()].join(", ")
+      ))?;
+      let mut rows = statement.query(params_from_iter(chunk.iter().map(|hash| &hash.0)))?;
+      while let Some(row) = rows.next()? {
+        found.entry(Hash(row.get(3)?)).or_default().push(peer_from_row(row)?);
+      }
+    }
+    Ok(
+      hashes
+        .iter()
+        .map(|hash| found.get(hash).cloned().unwrap_or_default())
+        .collect(),
+    )
+  }
+
+  fn get_hashes_for_peer(&self, address: &Address) -> Result<Vec<Hash>, Error> {
+    let reader = self.reader()?;
+    let mut statement = reader.prepare_cached(
+      "SELECT h.hash FROM peers p
+      JOIN peer_hashes ph ON ph.peer_pk = p.pk
+      JOIN hashes h ON h.pk = ph.hash_pk
+      WHERE p.address = ?",
+    )?;
+    let hashes = statement.query_map(params![address.to_string()], |row| Ok(Hash(row.get(0)?)))?;
+    Ok(hashes.collect::<Result<_, _>>()?)
+  }
+
+  fn get_hashes(&self) -> Result<Vec<(Hash, usize)>, Error> {
+    let reader = self.reader()?;
+    let mut statement = reader.prepare_cached(
+      "SELECT h.hash, COUNT(ph.peer_pk) FROM hashes h
+      JOIN peer_hashes ph ON ph.hash_pk = h.pk
+      GROUP BY h.pk",
+    )?;
+    let hashes = statement.query_map([], |row| Ok((Hash(row.get(0)?), row.get(1)?)))?;
+    Ok(hashes.collect::<Result<_, _>>()?)
+  }
+
+  fn get_peer_count(&self) -> Result<usize, Error> {
+    let reader = self.reader()?;
+    Ok(reader.query_row("SELECT COUNT(*) FROM peers", [], |row| row.get(0))?)
+  }
+
+  fn get_hash_count(&self) -> Result<usize, Error> {
+    let reader = self.reader()?;
+    Ok(reader.query_row("SELECT COUNT(*) FROM hashes", [], |row| row.get(0))?)
+  }
+
+  fn cleanup_peers(&self, timestamp: SystemTime) -> Result<usize, Error> {
+    let mut writer = self.writer();
+    let transaction = writer.transaction()?;
+    let timestamp = timestamp_to_unix(timestamp);
+    transaction.execute(
+      "DELETE FROM peer_hashes
+      WHERE peer_pk IN (SELECT pk FROM peers WHERE last_seen < ?)",
+      params![timestamp],
+    )?;
+    let removed =
+      transaction.execute("DELETE FROM peers WHERE last_seen < ?", params![timestamp])?;
+    transaction.commit()?;
+    Ok(removed)
+  }
+
+  fn cleanup_hashes(&self) -> Result<usize, Error> {
+    let removed = self.writer().execute(
+      "DELETE FROM hashes
+      WHERE NOT EXISTS (SELECT 1 FROM peer_hashes WHERE hash_pk = hashes.pk)",
+      [],
+    )?;
+    Ok(removed)
+  }
+}
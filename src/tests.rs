@@ -1,23 +1,132 @@
-use std::sync::{Arc, Mutex};
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+#[cfg(feature = "sql")]
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
 
 use futures::executor::block_on;
-use zeronet_protocol::{PeerAddr, ZeroConnection};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::sync::watch;
+use zeronet_peerdb::Peer;
+use zeronet_protocol::error::Error;
+use zeronet_protocol::message::{Request, Response, ZeroMessage};
+use zeronet_protocol::PeerAddr;
 
-use crate::shared_state::SharedState;
+use crate::address_filter::{classify, AddressClass, AddressFilter};
+use crate::args::get_arguments_from;
+use crate::peer_db::PeerStore;
+use crate::peer_handler::{pack_peers, ConnectionState, HandlerConfig};
+use crate::rate_limit::RateLimiter;
+use crate::reachability::ReachabilityChecker;
+use crate::sharded_peer_db::ShardedPeerDB;
+use crate::shared_state::{self, SharedState};
+use crate::shutdown;
+#[cfg(feature = "sql")]
+use crate::sqlite_peer_db::SqlitePeerDB;
 use crate::start_listener;
 
-fn start_tracker() {
+fn start_tracker(port: u16) {
+  // Dropping the trigger without sending leaves the tracker running
+  start_tracker_with_args(port, &[]);
+}
+
+fn start_tracker_with_args(
+  port: u16,
+  extra_args: &[&str],
+) -> (watch::Sender<bool>, JoinHandle<()>) {
   std::env::set_var("RUST_LOG", "zeronet_tracker=trace");
 
-  let shared_state = Arc::new(Mutex::new(SharedState::new()));
-  start_listener(&shared_state, "localhost".to_string(), 15442);
+  let port_arg = port.to_string();
+  let mut argv = vec!["zeronet_tracker", "--port", &port_arg];
+  argv.extend_from_slice(extra_args);
+  let args = get_arguments_from(argv);
+  let shared_state = Arc::new(RwLock::new(SharedState::new(&args)));
+  let (trigger, shutdown) = shutdown::channel();
+  let listener = std::thread::spawn(move || {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime.block_on(start_listener(
+      &shared_state,
+      "localhost".to_string(),
+      args.port,
+      args.max_connections,
+      HandlerConfig::from(&args),
+      shutdown,
+      Duration::from_secs(args.shutdown_timeout as u64),
+    ));
+  });
+  // Wait for the listener to bind
+  for _ in 0..50 {
+    if std::net::TcpStream::connect(("127.0.0.1", port)).is_ok() {
+      break;
+    }
+    std::thread::sleep(Duration::from_millis(100));
+  }
+  (trigger, listener)
+}
+
+/// A blocking peer connection. ZeroConnection hands a response over before
+/// its reader thread lets go of the socket, so a request sent right after
+/// it can wait forever.
+struct Client<S: Read + Write = std::net::TcpStream> {
+  stream:      S,
+  last_req_id: usize,
+}
+
+impl Client {
+  fn from_address(address: PeerAddr) -> Client {
+    let address: SocketAddr = address.try_into().unwrap();
+    Client::new(std::net::TcpStream::connect(address).unwrap())
+  }
+}
+
+impl<S: Read + Write> Client<S> {
+  fn new(stream: S) -> Client<S> {
+    Client {
+      stream,
+      last_req_id: 0,
+    }
+  }
+
+  fn request<T>(&mut self, cmd: &str, body: T) -> Result<Response, Error>
+  where
+    T: DeserializeOwned + Serialize,
+  {
+    self.last_req_id += 1;
+    let request = ZeroMessage::request(cmd, self.last_req_id, body);
+    rmp_serde::encode::write_named(&mut self.stream, &request)?;
+    loop {
+      match rmp_serde::from_read(&mut self.stream)? {
+        ZeroMessage::Response(res) if res.to == self.last_req_id => return Ok(res),
+        _ => continue,
+      }
+    }
+  }
+
+  fn recv(&mut self) -> Result<Request, Error> {
+    match rmp_serde::from_read(&mut self.stream)? {
+      ZeroMessage::Request(req) => Ok(req),
+      ZeroMessage::Response(_) => Err(Error::UnexpectedResponse),
+    }
+  }
+
+  fn respond<T>(&mut self, to: usize, body: T) -> Result<(), Error>
+  where
+    T: DeserializeOwned + Serialize,
+  {
+    let response = ZeroMessage::response(to, body);
+    Ok(rmp_serde::encode::write_named(&mut self.stream, &response)?)
+  }
 }
 
 fn handshake() -> serde_json::Value {
   let text = r#"
     {
       "crypt": null,
-      "crypt_supported": ["tls-rsa"],
       "fileserver_port": 15441,
       "onion": "zp2ynpztyxj2kw7x",
       "protocol": "v2",
@@ -27,38 +136,1374 @@ fn handshake() -> serde_json::Value {
       "target_ip": "192.168.1.13",
       "version": "0.5.6"
     }"#;
-  let value = serde_json::from_str(text).unwrap();
-  value
+  serde_json::from_str(text).unwrap()
 }
 
+/// An announce of a single site, as sent by a peer that asks for onions.
+/// Empty lists are left out, they reach the tracker as empty strings.
 fn announce() -> serde_json::Value {
-  let text = r#"
-    {
-      "hashes": [],
-      "onions": [],
-      "onion_signs": [],
-      "onion_sign_this": "",
+  serde_json::json!({
+    "hashes": [vec![4u8; 32]],
+    "port": 15441,
+    "need_types": ["ipv4"],
+    "need_num": 20,
+    "add": ["onion"]
+  })
+}
+
+#[test]
+fn test_handshake_with_onion() {
+  start_tracker(15442);
+
+  let address = PeerAddr::parse("127.0.0.1:15442".to_string()).unwrap();
+  let mut conn = Client::from_address(address);
+  let response = conn.request("handshake", handshake()).unwrap();
+  assert_eq!(response.to, conn.last_req_id);
+
+  let response = conn.request("announce", announce()).unwrap();
+  assert_eq!(response.to, conn.last_req_id);
+  let body: serde_json::Value = response.body().unwrap();
+  assert_eq!(body["error"], serde_json::Value::Null);
+  assert_eq!(body["peers"].as_array().unwrap().len(), 1);
+}
+
+fn ipv4_peers(count: u8) -> Vec<Peer> {
+  (0..count)
+    .map(|i| Peer {
+      address:    PeerAddr::IPV4([1, 2, 3, i], 15441),
+      date_added: SystemTime::now(),
+      last_seen:  SystemTime::now(),
+    })
+    .collect()
+}
+
+#[test]
+fn test_pack_peers_respects_limit() {
+  let need_types = vec!["ipv4".to_string()];
+  let packed = pack_peers(ipv4_peers(50), &need_types, 20);
+  assert_eq!(packed.ip_v4.len(), 20);
+
+  let packed = pack_peers(ipv4_peers(5), &need_types, 20);
+  assert_eq!(packed.ip_v4.len(), 5);
+}
+
+#[test]
+fn test_pack_peers_filters_need_types() {
+  let packed = pack_peers(ipv4_peers(5), &["ipv6".to_string()], 20);
+  assert!(packed.ip_v4.is_empty());
+
+  let packed = pack_peers(ipv4_peers(5), &["ip4".to_string()], 20);
+  assert_eq!(packed.ip_v4.len(), 5);
+}
+
+#[test]
+fn test_pack_peers_samples_randomly() {
+  let need_types = vec!["ipv4".to_string()];
+  let mut seen = HashSet::new();
+  for _ in 0..20 {
+    let packed = pack_peers(ipv4_peers(50), &need_types, 10);
+    seen.extend(packed.ip_v4);
+  }
+  assert!(seen.len() > 10);
+}
+
+#[cfg(feature = "tor")]
+fn onion_address(key: &ed25519_dalek::SigningKey) -> String {
+  let public_key = key.verifying_key().to_bytes();
+  let mut bytes = public_key.to_vec();
+  bytes.extend_from_slice(&crate::onion::checksum(&public_key));
+  bytes.push(3);
+  base32::encode(base32::Alphabet::RFC4648 { padding: false }, &bytes).to_lowercase()
+}
+
+#[test]
+#[cfg(feature = "tor")]
+fn test_onion_verify() {
+  use ed25519_dalek::{Signer, SigningKey};
+  use rand::rngs::OsRng;
+
+  let key = SigningKey::generate(&mut OsRng);
+  let other_key = SigningKey::generate(&mut OsRng);
+  let onion = onion_address(&key);
+  let sign = key.sign(b"challenge").to_bytes();
+
+  assert!(crate::onion::verify(&onion, "challenge", &sign));
+  assert!(crate::onion::verify(&format!("{}.onion", onion), "challenge", &sign));
+  assert!(!crate::onion::verify(&onion, "other challenge", &sign));
+  assert!(!crate::onion::verify(&onion_address(&other_key), "challenge", &sign));
+  assert!(!crate::onion::verify("zp2ynpztyxj2kw7x", "challenge", &sign));
+}
+
+#[test]
+#[cfg(feature = "tor")]
+fn test_announce_signed_onion() {
+  use ed25519_dalek::{Signer, SigningKey};
+  use rand::rngs::OsRng;
+
+  start_tracker(15443);
+
+  let key = SigningKey::generate(&mut OsRng);
+  let onion = onion_address(&key);
+  let mut announce = serde_json::json!({
+    "hashes": [vec![1u8; 32]],
+    "onions": [onion],
+    "port": 15441,
+    "need_types": ["onion"],
+    "need_num": 20,
+    "add": ["onion"]
+  });
+
+  let address = PeerAddr::parse("127.0.0.1:15443".to_string()).unwrap();
+  let mut conn = Client::from_address(address);
+
+  // Unsigned onions are not stored, the tracker asks for a signature instead
+  let response = conn.request("announce", announce.clone()).unwrap();
+  let body: serde_json::Value = response.body().unwrap();
+  let onion_sign_this = body["onion_sign_this"].as_str().unwrap().to_string();
+  assert_eq!(body["peers"][0]["onion"], serde_json::Value::Null);
+
+  // Signatures over anything but the challenge are rejected
+  let sign = key.sign(b"something else").to_bytes();
+  announce["onion_sign_this"] = serde_json::json!(onion_sign_this);
+  announce["onion_signs"] = serde_json::json!([sign.to_vec()]);
+  let response = conn.request("announce", announce.clone()).unwrap();
+  let body: serde_json::Value = response.body().unwrap();
+  assert!(body["error"].is_string());
+
+  let sign = key.sign(onion_sign_this.as_bytes()).to_bytes();
+  announce["onion_signs"] = serde_json::json!([sign.to_vec()]);
+  let response = conn.request("announce", announce).unwrap();
+  let body: serde_json::Value = response.body().unwrap();
+  assert_eq!(body["peers"][0]["onion"].as_array().unwrap().len(), 1);
+  assert_eq!(body["onion_sign_this"], serde_json::Value::Null);
+}
+
+#[test]
+#[cfg(feature = "tor")]
+fn test_handshake_onion_is_not_stored() {
+  start_tracker(15463);
+
+  let mut handshake = handshake();
+  let onion = "facebookwkhpilnemxj7asaniu7vnjjbiltxjqhye3mhbshg7kx5tfyd";
+  handshake["onion"] = serde_json::json!(onion);
+  let announce = serde_json::json!({
+    "hashes": [vec![10u8; 32]],
+    "port": 15441,
+    "need_types": ["onion"],
+    "need_num": 20,
+    "add": ["onion"]
+  });
+
+  let address = PeerAddr::parse("127.0.0.1:15463".to_string()).unwrap();
+  let mut conn = Client::from_address(address.clone());
+  conn.request("handshake", handshake).unwrap();
+  conn.request("announce", announce.clone()).unwrap();
+
+  // Claiming an onion in the handshake does not prove owning it
+  let mut other = Client::from_address(address);
+  let response = other.request("announce", announce).unwrap();
+  let body: serde_json::Value = response.body().unwrap();
+  assert_eq!(body["peers"][0]["onion"], serde_json::Value::Null);
+}
+
+#[test]
+#[cfg(feature = "tor")]
+fn test_announce_ip_and_onion() {
+  use ed25519_dalek::{Signer, SigningKey};
+  use rand::rngs::OsRng;
+
+  start_tracker_with_args(15454, &["--allow_addresses", "loopback"]);
+
+  let key = SigningKey::generate(&mut OsRng);
+  let mut announce = serde_json::json!({
+    "hashes": [vec![5u8; 32]],
+    "onions": [onion_address(&key)],
+    "port": 15441,
+    "need_types": ["ipv4", "onion"],
+    "need_num": 20,
+    "add": ["ipv4", "onion"]
+  });
+
+  let address = PeerAddr::parse("127.0.0.1:15454".to_string()).unwrap();
+  let mut conn = Client::from_address(address);
+  let response = conn.request("announce", announce.clone()).unwrap();
+  let body: serde_json::Value = response.body().unwrap();
+  let onion_sign_this = body["onion_sign_this"].as_str().unwrap().to_string();
+
+  let sign = key.sign(onion_sign_this.as_bytes()).to_bytes();
+  announce["onion_sign_this"] = serde_json::json!(onion_sign_this);
+  announce["onion_signs"] = serde_json::json!([sign.to_vec()]);
+  let response = conn.request("announce", announce).unwrap();
+  let body: serde_json::Value = response.body().unwrap();
+  assert_eq!(body["peers"][0]["ipv4"].as_array().unwrap().len(), 1);
+  assert_eq!(body["peers"][0]["onion"].as_array().unwrap().len(), 1);
+}
+
+#[cfg(feature = "i2p")]
+fn i2p_peer(address: &str) -> Peer {
+  Peer {
+    address:    PeerAddr::parse(format!("{}.b32.i2p:15441", address)).unwrap(),
+    date_added: SystemTime::now(),
+    last_seen:  SystemTime::now(),
+  }
+}
+
+#[test]
+#[cfg(feature = "i2p")]
+fn test_pack_peers_i2p() {
+  let address = "ukeu3k5oycgaauneqgtnvselmt4yemvoilkln7jpvamvfx7dnkdq";
+  let mut peers = ipv4_peers(3);
+  peers.push(i2p_peer(address));
+
+  let packed = pack_peers(peers.clone(), &["i2p".to_string()], 20);
+  assert!(packed.ip_v4.is_empty());
+  assert_eq!(packed.i2p_b32.len(), 1);
+  let unpacked = PeerAddr::unpack(&packed.i2p_b32[0]).unwrap();
+  assert_eq!(unpacked, peers[3].address);
+
+  let packed = pack_peers(peers, &["ipv4".to_string()], 20);
+  assert_eq!(packed.ip_v4.len(), 3);
+  assert!(packed.i2p_b32.is_empty());
+}
+
+#[test]
+#[cfg(feature = "i2p")]
+fn test_announce_i2p() {
+  start_tracker(15444);
+
+  let address = "ukeu3k5oycgaauneqgtnvselmt4yemvoilkln7jpvamvfx7dnkdq";
+  let mut handshake = handshake();
+  handshake.as_object_mut().unwrap().remove("onion");
+  handshake["i2p"] = serde_json::json!(address);
+  let announce = serde_json::json!({
+    "hashes": [vec![2u8; 32]],
+    "port": 15441,
+    "need_types": ["i2p"],
+    "need_num": 20,
+    "add": ["i2p"],
+  });
+
+  let tracker = PeerAddr::parse("127.0.0.1:15444".to_string()).unwrap();
+  let mut conn = Client::from_address(tracker);
+  conn.request("handshake", handshake).unwrap();
+  let response = conn.request("announce", announce).unwrap();
+  let body: zeronet_protocol::templates::AnnounceResponse = response.body().unwrap();
+  // Claiming an I2P address in the handshake does not prove owning it
+  assert!(body.peers[0].i2p_b32.is_empty());
+}
+
+#[test]
+fn test_ping() {
+  start_tracker(15445);
+
+  let address = PeerAddr::parse("127.0.0.1:15445".to_string()).unwrap();
+  let mut conn = Client::from_address(address);
+  let response = conn.request("ping", serde_json::json!({})).unwrap();
+  let body: serde_json::Value = response.body().unwrap();
+  assert_eq!(body["body"], "Pong!");
+}
+
+#[test]
+fn test_classify_addresses() {
+  let cases = [
+    ("8.8.8.8:1", AddressClass::Public),
+    ("192.0.1.1:1", AddressClass::Public),
+    ("172.32.0.1:1", AddressClass::Public),
+    ("127.0.0.1:1", AddressClass::Loopback),
+    ("10.1.2.3:1", AddressClass::Private),
+    ("172.16.0.1:1", AddressClass::Private),
+    ("172.31.255.255:1", AddressClass::Private),
+    ("192.168.1.13:1", AddressClass::Private),
+    ("169.254.0.1:1", AddressClass::LinkLocal),
+    ("100.64.0.1:1", AddressClass::Shared),
+    ("100.128.0.1:1", AddressClass::Public),
+    ("0.0.0.0:1", AddressClass::Reserved),
+    ("224.0.0.1:1", AddressClass::Reserved),
+    ("255.255.255.255:1", AddressClass::Reserved),
+    ("[2a00:1450::1]:1", AddressClass::Public),
+    ("[::1]:1", AddressClass::Loopback),
+    ("[::]:1", AddressClass::Reserved),
+    ("[fd12:3456::1]:1", AddressClass::Private),
+    ("[fe80::1]:1", AddressClass::LinkLocal),
+    ("[ff02::1]:1", AddressClass::Reserved),
+    ("[2001:db8::1]:1", AddressClass::Reserved),
+    ("[::ffff:10.0.0.1]:1", AddressClass::Private),
+    ("[::ffff:8.8.8.8]:1", AddressClass::Public),
+  ];
+  for (address, class) in cases.iter() {
+    let address = PeerAddr::parse(*address).unwrap();
+    assert_eq!(classify(&address), *class, "{}", address);
+  }
+}
+
+#[test]
+fn test_address_filter() {
+  let public = PeerAddr::parse("8.8.8.8:1").unwrap();
+  let private = PeerAddr::parse("10.0.0.1:1").unwrap();
+  let reserved = PeerAddr::parse("0.0.0.0:1").unwrap();
+
+  let filter = AddressFilter::new(vec![]);
+  assert!(filter.accepts(&public));
+  assert!(!filter.accepts(&private));
+  assert!(!filter.accepts(&reserved));
+
+  let args = get_arguments_from(vec!["zeronet_tracker", "--allow_addresses", "private,loopback"]);
+  let filter = AddressFilter::new(args.allowed_addresses);
+  assert!(filter.accepts(&private));
+  assert!(!filter.accepts(&reserved));
+}
+
+#[test]
+fn test_connection_limit() {
+  let limiter = RateLimiter::new(2, 0, 0);
+  let ip = "127.0.0.1".parse().unwrap();
+  let first = limiter.acquire_connection(ip);
+  let second = limiter.acquire_connection(ip);
+  assert!(first.is_some() && second.is_some());
+  assert!(limiter.acquire_connection(ip).is_none());
+  assert!(limiter.acquire_connection("127.0.0.2".parse().unwrap()).is_some());
+
+  drop(first);
+  assert!(limiter.acquire_connection(ip).is_some());
+}
+
+#[test]
+fn test_refused_connection_gets_error() {
+  start_tracker_with_args(15464, &["--max_connections_per_ip", "1"]);
+  // Let the connections made while waiting for the listener close
+  std::thread::sleep(Duration::from_millis(200));
+
+  let tracker = PeerAddr::parse("127.0.0.1:15464".to_string()).unwrap();
+  let mut first = Client::from_address(tracker.clone());
+  first.request("ping", serde_json::json!({})).unwrap();
+
+  let mut second = Client::from_address(tracker);
+  let response = second.request("ping", serde_json::json!({})).unwrap();
+  let body: zeronet_protocol::templates::Error = response.body().unwrap();
+  assert_eq!(body.error, "Too many connections");
+}
+
+#[test]
+fn test_request_rate_limit() {
+  let limiter = RateLimiter::new(0, 1, 3);
+  let ip = "127.0.0.1".parse().unwrap();
+  for _ in 0..3 {
+    assert!(limiter.allow_request(ip));
+  }
+  assert!(!limiter.allow_request(ip));
+  assert!(limiter.allow_request("127.0.0.2".parse().unwrap()));
+
+  std::thread::sleep(Duration::from_millis(1100));
+  assert!(limiter.allow_request(ip));
+  assert!(!limiter.allow_request(ip));
+}
+
+#[test]
+fn test_idle_timeout() {
+  start_tracker_with_args(15449, &["--idle_timeout", "1"]);
+
+  let mut stream = std::net::TcpStream::connect("127.0.0.1:15449").unwrap();
+  stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+  let start = std::time::Instant::now();
+  // The tracker closes the connection without ever receiving a request
+  assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+  assert!(start.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn test_read_timeout() {
+  start_tracker_with_args(15462, &["--read_timeout", "1", "--idle_timeout", "30"]);
+
+  let mut stream = std::net::TcpStream::connect("127.0.0.1:15462").unwrap();
+  stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+  let mut conn = Client::new(stream.try_clone().unwrap());
+  // Idling longer than the read timeout is fine
+  std::thread::sleep(Duration::from_secs(2));
+  conn.request("ping", serde_json::json!({})).unwrap();
+
+  // A request that stops halfway is not
+  let ping = serde_json::json!({"cmd": "ping", "req_id": 2, "params": {}});
+  let ping = rmp_serde::to_vec_named(&ping).unwrap();
+  stream.write_all(&ping[..ping.len() / 2]).unwrap();
+  let start = std::time::Instant::now();
+  assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+  assert!(start.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn test_graceful_shutdown() {
+  let (trigger, listener) = start_tracker_with_args(15450, &[]);
+
+  let mut stream = std::net::TcpStream::connect("127.0.0.1:15450").unwrap();
+  stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+  let mut conn = Client::new(stream.try_clone().unwrap());
+  conn.request("ping", serde_json::json!({})).unwrap();
+
+  trigger.send(true).unwrap();
+  // Open connections are closed and the listener returns
+  assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+  listener.join().unwrap();
+  assert!(std::net::TcpStream::connect("127.0.0.1:15450").is_err());
+}
+
+#[test]
+fn test_recover_poisoned_state() {
+  let args = get_arguments_from(vec!["zeronet_tracker"]);
+  let shared_state = Arc::new(RwLock::new(SharedState::new(&args)));
+
+  let moved_state = shared_state.clone();
+  let result = std::thread::spawn(move || {
+    let _guard = moved_state.write().unwrap();
+    panic!("Panicking while holding the lock");
+  })
+  .join();
+  assert!(result.is_err());
+  assert!(shared_state.is_poisoned());
+
+  let state = shared_state::read(&shared_state);
+  assert_eq!(state.peer_db.get_peer_count().unwrap(), 0);
+  drop(state);
+  assert!(!shared_state.is_poisoned());
+
+  let moved_state = shared_state.clone();
+  let result = std::thread::spawn(move || {
+    let _guard = moved_state.write().unwrap();
+    panic!("Panicking while holding the lock");
+  })
+  .join();
+  assert!(result.is_err());
+
+  let state = shared_state::write(&shared_state);
+  assert_eq!(state.peer_db.get_hash_count().unwrap(), 0);
+  drop(state);
+  assert!(!shared_state.is_poisoned());
+}
+
+#[test]
+fn test_handshake_response() {
+  start_tracker_with_args(15451, &["--peer_id", "-ZT0000-trackertest"]);
+
+  let address = PeerAddr::parse("127.0.0.1:15451".to_string()).unwrap();
+  let mut conn = Client::from_address(address);
+  let mut handshake = handshake();
+  handshake.as_object_mut().unwrap().remove("onion");
+  let response = conn.request("handshake", handshake).unwrap();
+  let body: zeronet_protocol::templates::Handshake = response.body().unwrap();
+  assert_eq!(body.peer_id, "-ZT0000-trackertest");
+  assert_eq!(body.version, clap::crate_version!());
+  assert_eq!(body.fileserver_port, 15451);
+  assert_eq!(body.target_address, Some("127.0.0.1".to_string()));
+}
+
+#[test]
+fn test_connection_state_transitions() {
+  use ConnectionState::*;
+
+  assert_eq!(New.handshake(), Handshaken);
+  assert_eq!(Handshaken.handshake(), Handshaken);
+  assert_eq!(Active.handshake(), Handshaken);
+
+  assert_eq!(New.announce(false), Some(Active));
+  assert_eq!(New.announce(true), None);
+  assert_eq!(Handshaken.announce(true), Some(Active));
+  assert_eq!(Active.announce(true), Some(Active));
+}
+
+#[test]
+fn test_strict_mode_requires_handshake() {
+  start_tracker_with_args(15452, &["--require_handshake"]);
+
+  let mut handshake = handshake();
+  handshake.as_object_mut().unwrap().remove("onion");
+  let announce = serde_json::json!({
+    "hashes": [vec![3u8; 32]],
+    "port": 15441,
+    "need_types": ["ipv4"],
+    "need_num": 20,
+  });
+
+  let tracker = PeerAddr::parse("127.0.0.1:15452".to_string()).unwrap();
+  let mut conn = Client::from_address(tracker);
+  let response = conn.request("announce", announce.clone()).unwrap();
+  let body: zeronet_protocol::templates::Error = response.body().unwrap();
+  assert_eq!(body.error, "Handshake required before announce");
+
+  conn.request("handshake", handshake).unwrap();
+  let response = conn.request("announce", announce).unwrap();
+  let body: zeronet_protocol::templates::AnnounceResponse = response.body().unwrap();
+  assert_eq!(body.peers.len(), 1);
+}
+
+#[test]
+fn test_announce_add_types() {
+  start_tracker_with_args(15453, &["--allow_addresses", "loopback"]);
+
+  let mut announce = serde_json::json!({
+    "hashes": [vec![4u8; 32]],
+    "port": 15441,
+    "need_types": ["ipv4"],
+    "need_num": 20,
+    "add": ["ipv6"]
+  });
+
+  let address = PeerAddr::parse("127.0.0.1:15453".to_string()).unwrap();
+  let mut conn = Client::from_address(address);
+  // The peer connected over IPv4, but only asked for IPv6 to be added
+  let response = conn.request("announce", announce.clone()).unwrap();
+  let body: serde_json::Value = response.body().unwrap();
+  assert_eq!(body["peers"][0]["ipv4"], serde_json::Value::Null);
+
+  announce["add"] = serde_json::json!(["ipv6", "ipv4"]);
+  let response = conn.request("announce", announce).unwrap();
+  let body: serde_json::Value = response.body().unwrap();
+  assert_eq!(body["peers"][0]["ipv4"].as_array().unwrap().len(), 1);
+}
+
+/// A database file in the temp directory, removed first if it is left over
+/// from an earlier run.
+#[cfg(feature = "sql")]
+pub fn temp_database(name: &str) -> PathBuf {
+  let file = format!("zeronet_tracker_{}_{}.db", name, std::process::id());
+  let path = std::env::temp_dir().join(file);
+  for suffix in ["", "-wal", "-shm"] {
+    let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+  }
+  path
+}
+
+/// An empty instance of every peer database backend.
+#[cfg_attr(not(feature = "sql"), allow(unused_variables))]
+fn peer_stores(name: &str) -> Vec<Box<dyn PeerStore>> {
+  #[allow(unused_mut)]
+  let mut peer_stores: Vec<Box<dyn PeerStore>> = vec![Box::new(ShardedPeerDB::new())];
+  #[cfg(feature = "sql")]
+  peer_stores.push(Box::new(SqlitePeerDB::open(&temp_database(name)).unwrap()));
+  peer_stores
+}
+
+#[test]
+fn test_replace_peer() {
+  use zeronet_peerdb::Hash;
+
+  let first_seen = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+  let peer = |date_added| Peer {
+    address: PeerAddr::parse("1.2.3.4:15441").unwrap(),
+    date_added,
+    last_seen: SystemTime::now(),
+  };
+  let peers_for = |peer_db: &dyn PeerStore, hash: u8| {
+    peer_db.get_peers_for_hash(&Hash(vec![hash; 32])).unwrap().len()
+  };
+  let hashes = |hashes: &[u8]| hashes.iter().map(|h| Hash(vec![*h; 32])).collect::<Vec<_>>();
+
+  for peer_db in peer_stores("replace_peer") {
+    let peer_db = peer_db.as_ref();
+    assert!(!peer_db.replace_peer(&peer(first_seen), &hashes(&[1, 2])).unwrap());
+
+    // A partial re-announce adds to the hashes of the peer
+    peer_db.update_peer(&peer(first_seen), &hashes(&[3])).unwrap();
+    assert_eq!(peers_for(peer_db, 1), 1);
+    assert_eq!(peers_for(peer_db, 3), 1);
+
+    // A full re-announce replaces them, but keeps when the peer was first added
+    assert!(peer_db.replace_peer(&peer(SystemTime::now()), &hashes(&[2, 4])).unwrap());
+    assert_eq!(peers_for(peer_db, 1), 0);
+    assert_eq!(peers_for(peer_db, 2), 1);
+    assert_eq!(peers_for(peer_db, 3), 0);
+    assert_eq!(peers_for(peer_db, 4), 1);
+    let stored = peer_db.get_peer(&peer(first_seen).address).unwrap().unwrap();
+    assert_eq!(stored.date_added, first_seen);
+  }
+}
+
+#[test]
+fn test_replace_peer_is_atomic() {
+  use zeronet_peerdb::Hash;
+
+  let hash = |hash: u8| Hash(vec![hash; 32]);
+  let peer = ipv4_peers(1).pop().unwrap();
+  for peer_db in peer_stores("replace_peer_is_atomic") {
+    let peer_db: Arc<dyn PeerStore> = Arc::from(peer_db);
+    peer_db.update_peer(&peer, &[hash(1)]).unwrap();
+    // Replacing the other hashes never takes the peer off the hash it keeps
+    let replacing = {
+      let (peer_db, peer) = (peer_db.clone(), peer.clone());
+      std::thread::spawn(move || {
+        for i in 0..5000 {
+          peer_db.replace_peer(&peer, &[hash(1), hash(2 + (i % 2) as u8)]).unwrap();
+        }
+      })
+    };
+    while !replacing.is_finished() {
+      assert_eq!(peer_db.get_peers_for_hash(&hash(1)).unwrap().len(), 1);
+    }
+    replacing.join().unwrap();
+    assert_eq!(peer_db.get_peers_for_hash(&hash(2)).unwrap().len(), 0);
+    assert_eq!(peer_db.get_peers_for_hash(&hash(3)).unwrap().len(), 1);
+  }
+}
+
+#[test]
+fn test_batched_peer_db() {
+  use zeronet_peerdb::Hash;
+
+  let hash = |hash: u8| Hash(vec![hash; 32]);
+  let updates: Vec<(Peer, Vec<Hash>)> = ipv4_peers(3)
+    .into_iter()
+    .zip([vec![hash(1)], vec![hash(1), hash(2)], vec![hash(3)]])
+    .collect();
+
+  for peer_db in peer_stores("batched_peer_db") {
+    assert_eq!(peer_db.update_peers(&updates[..2]).unwrap(), vec![false, false]);
+    assert_eq!(peer_db.update_peers(&updates).unwrap().len(), 3);
+    assert_eq!(peer_db.get_peer_count().unwrap(), 3);
+
+    let found = peer_db
+      .get_peers_for_hashes(&[hash(3), hash(1), hash(4), hash(1)])
+      .unwrap();
+    let counts: Vec<usize> = found.iter().map(|peers| peers.len()).collect();
+    assert_eq!(counts, vec![1, 2, 0, 2]);
+    assert_eq!(found[0][0].address, updates[2].0.address);
+
+    // Large announces are looked up in several chunks
+    let hashes: Vec<Hash> = (0..1200u16).map(|h| Hash(h.to_be_bytes().to_vec())).collect();
+    let hashes = [&hashes[..], &[hash(2)]].concat();
+    let found = peer_db.get_peers_for_hashes(&hashes).unwrap();
+    assert_eq!(found.len(), 1201);
+    assert_eq!(found[1200].len(), 1);
+  }
+}
+
+#[test]
+fn test_peer_store() {
+  use zeronet_peerdb::Hash;
+
+  let hash = |hash: u8| Hash(vec![hash; 32]);
+  let peers = ipv4_peers(3);
+  for peer_db in peer_stores("peer_store") {
+    peer_db.update_peer(&peers[0], &[hash(1), hash(2)]).unwrap();
+    peer_db.update_peer(&peers[1], &[hash(2)]).unwrap();
+    assert_eq!(peer_db.get_peer_count().unwrap(), 2);
+    let mut counts = peer_db.get_hashes().unwrap();
+    counts.sort();
+    assert_eq!(counts, vec![(hash(1), 1), (hash(2), 2)]);
+    let mut hashes = peer_db.get_hashes_for_peer(&peers[0].address).unwrap();
+    hashes.sort();
+    assert_eq!(hashes, vec![hash(1), hash(2)]);
+    assert!(peer_db.get_hashes_for_peer(&peers[2].address).unwrap().is_empty());
+
+    // Unlinking leaves the other hashes of the peer alone
+    peer_db.update_peer(&peers[2], &[hash(1), hash(2)]).unwrap();
+    assert!(peer_db.unlink_peer(&hash(1), &peers[2].address).unwrap());
+    assert!(!peer_db.unlink_peer(&hash(1), &peers[2].address).unwrap());
+    assert_eq!(peer_db.get_hashes_for_peer(&peers[2].address).unwrap(), vec![hash(2)]);
+    // and the peer goes away with its last hash
+    assert!(peer_db.unlink_peer(&hash(2), &peers[2].address).unwrap());
+    assert!(peer_db.get_peer(&peers[2].address).unwrap().is_none());
+
+    assert!(peer_db.remove_peer(&peers[0].address).unwrap().is_some());
+    assert!(peer_db.remove_peer(&peers[0].address).unwrap().is_none());
+    assert!(peer_db.get_peer(&peers[0].address).unwrap().is_none());
+    peer_db.cleanup_hashes().unwrap();
+    assert_eq!(peer_db.get_hash_count().unwrap(), 1);
+
+    // Only peers that have not announced since are cleaned up
+    let stale = Peer {
+      last_seen: SystemTime::now() - Duration::from_secs(3600),
+      ..peers[2].clone()
+    };
+    peer_db.update_peer(&stale, &[hash(3)]).unwrap();
+    let cutoff = SystemTime::now() - Duration::from_secs(60);
+    assert_eq!(peer_db.cleanup_peers(cutoff).unwrap(), 1);
+    peer_db.cleanup_hashes().unwrap();
+    assert_eq!(peer_db.get_peers().unwrap().len(), 1);
+    assert_eq!(peer_db.get_hash_count().unwrap(), 1);
+  }
+}
+
+/// Accepts a single connection and answers its handshake like a ZeroNet
+/// fileserver, or with an error unless the handshake is addressed to its IP.
+fn start_fake_peer(port: u16) {
+  let listener = std::net::TcpListener::bind(("127.0.0.1", port)).unwrap();
+  std::thread::spawn(move || {
+    let (stream, _) = listener.accept().unwrap();
+    let mut conn = Client::new(stream);
+    let req = conn.recv().unwrap();
+    let handshake: zeronet_protocol::templates::Handshake = req.body().unwrap();
+    if handshake.target_address.as_deref() == Some("127.0.0.1") {
+      let body = zeronet_protocol::templates::Handshake::new();
+      conn.respond(req.req_id, body).unwrap();
+    } else {
+      let body = serde_json::json!({ "error": "Unknown target_ip" });
+      conn.respond(req.req_id, body).unwrap();
+    }
+  });
+}
+
+#[test]
+fn test_reachability_check() {
+  let runtime = tokio::runtime::Runtime::new().unwrap();
+  let checker = ReachabilityChecker::new(Duration::from_secs(60));
+  let peer = PeerAddr::parse("127.0.0.1:15446").unwrap();
+  let closed = PeerAddr::parse("127.0.0.1:15447").unwrap();
+
+  start_fake_peer(15446);
+  assert!(runtime.block_on(checker.is_reachable(&peer)));
+  assert!(!runtime.block_on(checker.is_reachable(&closed)));
+
+  // The fake peer is gone, but the earlier result is still cached
+  assert!(runtime.block_on(checker.is_reachable(&peer)));
+  let uncached = ReachabilityChecker::new(Duration::from_secs(0));
+  assert!(!runtime.block_on(uncached.is_reachable(&peer)));
+
+  // A reply that is not a handshake does not count
+  let listener = std::net::TcpListener::bind("127.0.0.1:15465").unwrap();
+  std::thread::spawn(move || {
+    let (stream, _) = listener.accept().unwrap();
+    let mut conn = Client::new(stream);
+    let req = conn.recv().unwrap();
+    conn.respond(req.req_id, serde_json::json!({ "error": "Unknown cmd" })).unwrap();
+  });
+  let other = PeerAddr::parse("127.0.0.1:15465").unwrap();
+  assert!(!runtime.block_on(checker.is_reachable(&other)));
+}
+
+#[test]
+#[cfg(feature = "tls")]
+fn test_handshake_with_tls() {
+  use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+
+  start_tracker(15448);
+
+  let stream = std::net::TcpStream::connect("127.0.0.1:15448").unwrap();
+  let mut conn = Client::new(stream.try_clone().unwrap());
+  let mut handshake = handshake();
+  handshake.as_object_mut().unwrap().remove("onion");
+  handshake["crypt_supported"] = serde_json::json!(["tls-rsa"]);
+  let response = conn.request("handshake", handshake).unwrap();
+  let body: zeronet_protocol::templates::Handshake = response.body().unwrap();
+  assert_eq!(body.crypt, Some("tls-rsa".to_string()));
+
+  // ZeroNet does not verify the self-signed certificates of peers
+  let mut connector = SslConnector::builder(SslMethod::tls_client()).unwrap();
+  connector.set_verify(SslVerifyMode::NONE);
+  let stream = connector.build().connect("zeronet_tracker", stream).unwrap();
+  let mut conn = Client::new(stream);
+  let response = conn.request("ping", serde_json::json!({})).unwrap();
+  let body: serde_json::Value = response.body().unwrap();
+  assert_eq!(body["body"], "Pong!");
+}
+
+#[test]
+fn test_bencode() {
+  use crate::http_tracker::Bencode;
+
+  let value = Bencode::dict(vec![
+    ("peers", Bencode::List(vec![Bencode::Int(-3), Bencode::Bytes(b"ab".to_vec())])),
+    ("interval", Bencode::Int(1800)),
+  ]);
+  assert_eq!(value.encode(), b"d8:intervali1800e5:peersli-3e2:abee".to_vec());
+
+  let failure = crate::http_tracker::failure_response("Invalid event");
+  assert_eq!(failure.encode(), b"d14:failure reason13:Invalid evente".to_vec());
+}
+
+#[test]
+fn test_bittorrent_announce_query() {
+  use crate::bittorrent::Event;
+  use crate::http_tracker::parse_announce;
+
+  let query = "info_hash=%12%34Vx%9a%bc%de%f1%23Eg%89%ab%cd%ef%124Vx%9a\
+    &peer_id=-TR2940-k8hj0wgej6ch&port=6881&event=started&compact=1&numwant=10";
+  let (request, compact) = parse_announce(query).unwrap();
+  assert_eq!(
+    request.info_hash.0,
+    b"\x12\x34Vx\x9a\xbc\xde\xf1\x23Eg\x89\xab\xcd\xef\x124Vx\x9a".to_vec()
+  );
+  assert_eq!(request.port, 6881);
+  assert_eq!(request.event, Some(Event::Started));
+  assert_eq!(request.numwant, Some(10));
+  assert!(compact);
+
+  assert!(parse_announce("info_hash=short&peer_id=-TR2940-k8hj0wgej6ch").is_err());
+  assert!(parse_announce(&query.replace("port=6881", "port=none")).is_err());
+}
+
+#[test]
+fn test_bittorrent_announce_and_scrape() {
+  use crate::bittorrent::{self, AnnounceRequest, Event, TrackerConfig};
+  use crate::http_tracker::{self, Bencode};
+  use zeronet_peerdb::Hash;
+
+  let args = get_arguments_from(vec!["zeronet_tracker", "--allow_addresses", "loopback"]);
+  let shared_state = RwLock::new(SharedState::new(&args));
+  let config = TrackerConfig::from(&args);
+  let request = |port, event| AnnounceRequest {
+    info_hash: Hash(vec![7; 20]),
+    port,
+    event,
+    numwant: None,
+  };
+  let local = "127.0.0.1".parse().unwrap();
+
+  let swarm = bittorrent::announce(&shared_state, &config, local, &request(6881, None)).unwrap();
+  assert_eq!((swarm.size, swarm.peers.len()), (1, 0));
+
+  // The second peer gets the first one, packed in network byte order
+  let ipv6 = "::ffff:127.0.0.1".parse().unwrap();
+  let swarm = bittorrent::announce(&shared_state, &config, ipv6, &request(6882, None)).unwrap();
+  assert_eq!(swarm.size, 2);
+  let response = http_tracker::announce_response(&config, &swarm, true);
+  let expected = Bencode::dict(vec![
+    ("interval", Bencode::Int(1500)),
+    ("complete", Bencode::Int(2)),
+    ("incomplete", Bencode::Int(0)),
+    ("peers", Bencode::Bytes(vec![127, 0, 0, 1, 0x1a, 0xe1])),
+  ]);
+  assert_eq!(response, expected);
+
+  // A stopped peer is not stored
+  let stopped = request(6883, Some(Event::Stopped));
+  let swarm = bittorrent::announce(&shared_state, &config, local, &stopped).unwrap();
+  assert_eq!(swarm.size, 2);
+
+  let hashes = vec![Hash(vec![7; 20]), Hash(vec![8; 20])];
+  let counts = bittorrent::scrape(&shared_state, &config, local, &hashes).unwrap();
+  assert_eq!(counts, vec![2, 0]);
+  let response = http_tracker::scrape_response(&hashes, &counts).encode();
+  assert!(response.starts_with(b"d5:filesd20:\x07\x07"));
+}
+
+#[test]
+fn test_udp_connection_ids() {
+  use crate::udp_tracker::ConnectionIds;
+
+  let connection_ids = ConnectionIds::new();
+  let client: std::net::SocketAddr = "127.0.0.1:6881".parse().unwrap();
+  let other: std::net::SocketAddr = "127.0.0.1:6882".parse().unwrap();
+  let issued_at = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+  let id = connection_ids.issue(&client, issued_at);
+
+  assert!(connection_ids.is_valid(id, &client, issued_at));
+  assert!(connection_ids.is_valid(id, &client, issued_at + Duration::from_secs(60)));
+  assert!(!connection_ids.is_valid(id, &client, issued_at + Duration::from_secs(120)));
+  assert!(!connection_ids.is_valid(id, &other, issued_at));
+}
+
+#[test]
+fn test_udp_tracker() {
+  use crate::bittorrent::TrackerConfig;
+  use crate::udp_tracker;
+
+  let args = get_arguments_from(vec!["zeronet_tracker", "--allow_addresses", "loopback"]);
+  let shared_state = Arc::new(RwLock::new(SharedState::new(&args)));
+  let config = TrackerConfig::from(&args);
+  let (trigger, shutdown) = shutdown::channel();
+  let tracker = std::thread::spawn(move || {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime.block_on(async {
+      let socket = tokio::net::UdpSocket::bind("127.0.0.1:15455").await.unwrap();
+      udp_tracker::run(shared_state, socket, config, shutdown).await;
+    });
+  });
+
+  let client = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+  client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+  let exchange = |request: Vec<u8>| {
+    let mut buf = [0; 2048];
+    for _ in 0..50 {
+      client.send_to(&request, "127.0.0.1:15455").unwrap();
+      if let Ok(len) = client.recv(&mut buf) {
+        return buf[..len].to_vec();
+      }
+    }
+    panic!("No response from UDP tracker");
+  };
+  let header = |connection_id: u64, action: u32| {
+    let mut request = connection_id.to_be_bytes().to_vec();
+    request.extend(action.to_be_bytes());
+    request.extend(7u32.to_be_bytes());
+    request
+  };
+
+  let response = exchange(header(0x41727101980, 0));
+  assert_eq!(response[..8], [0, 0, 0, 0, 0, 0, 0, 7]);
+  let connection_id = u64::from_be_bytes(response[8..16].try_into().unwrap());
+
+  let mut announce = header(connection_id, 1);
+  announce.extend([9; 20]);
+  announce.extend(b"-TR2940-k8hj0wgej6ch");
+  announce.extend([0; 24]);
+  announce.extend(2u32.to_be_bytes());
+  announce.extend([0; 8]);
+  announce.extend((-1i32).to_be_bytes());
+  announce.extend(6881u16.to_be_bytes());
+  let response = exchange(announce);
+  assert_eq!(response[..8], [0, 0, 0, 1, 0, 0, 0, 7]);
+  assert_eq!(response[8..12], 1500u32.to_be_bytes());
+  assert_eq!(response[16..20], 1u32.to_be_bytes());
+  assert_eq!(response.len(), 20);
+
+  let mut scrape = header(connection_id, 2);
+  scrape.extend([9; 20]);
+  scrape.extend([8; 20]);
+  let response = exchange(scrape);
+  assert_eq!(response[..8], [0, 0, 0, 2, 0, 0, 0, 7]);
+  assert_eq!(response[8..], [0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+  let response = exchange(header(connection_id ^ 1, 2));
+  assert_eq!(response[..8], [0, 0, 0, 3, 0, 0, 0, 7]);
+  assert_eq!(&response[8..], b"Invalid connection ID");
+
+  trigger.send(true).unwrap();
+  tracker.join().unwrap();
+}
+
+#[test]
+fn test_proxy_protocol_header() {
+  use crate::proxy_protocol::read_header;
+
+  let mut v1: &[u8] = b"PROXY TCP4 1.2.3.4 10.0.0.1 43210 15441\r\nrest";
+  let client = block_on(read_header(&mut v1)).unwrap();
+  assert_eq!(client, Some("1.2.3.4:43210".parse().unwrap()));
+  assert_eq!(v1, b"rest");
+
+  let mut v1: &[u8] = b"PROXY TCP6 2001:db8::1 ::1 43210 15441\r\n";
+  let client = block_on(read_header(&mut v1)).unwrap();
+  assert_eq!(client, Some("[2001:db8::1]:43210".parse().unwrap()));
+
+  let mut unknown: &[u8] = b"PROXY UNKNOWN\r\n";
+  assert_eq!(block_on(read_header(&mut unknown)).unwrap(), None);
+
+  let mut v2 = b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0c".to_vec();
+  v2.extend([1, 2, 3, 4, 10, 0, 0, 1, 0xa8, 0xca, 0x3c, 0x51]);
+  v2.extend(b"rest");
+  let mut v2: &[u8] = &v2;
+  let client = block_on(read_header(&mut v2)).unwrap();
+  assert_eq!(client, Some("1.2.3.4:43210".parse().unwrap()));
+  assert_eq!(v2, b"rest");
+
+  let mut local: &[u8] = b"\r\n\r\n\0\r\nQUIT\n\x20\x00\x00\x00";
+  assert_eq!(block_on(read_header(&mut local)).unwrap(), None);
+
+  let mut mismatch: &[u8] = b"PROXY TCP4 2001:db8::1 ::1 43210 15441\r\n";
+  assert!(block_on(read_header(&mut mismatch)).is_err());
+  let mut missing: &[u8] = b"\x82\xa3cmd\xa9handshake\xa6req_id\x00";
+  assert!(block_on(read_header(&mut missing)).is_err());
+  let mut too_long = b"PROXY TCP4 ".to_vec();
+  too_long.extend([b'1'; 120]);
+  let mut too_long: &[u8] = &too_long;
+  assert!(block_on(read_header(&mut too_long)).is_err());
+}
+
+#[test]
+fn test_proxied_connection() {
+  start_tracker_with_args(15456, &["--trusted_proxies", "127.0.0.1"]);
+  let connect = |header: &[u8]| {
+    let mut stream = std::net::TcpStream::connect("127.0.0.1:15456").unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    stream.write_all(header).unwrap();
+    Client::new(stream)
+  };
+  let mut handshake = handshake();
+  handshake.as_object_mut().unwrap().remove("onion");
+
+  // The tracker sees the client named in the header
+  let mut conn = connect(b"PROXY TCP4 1.2.3.4 127.0.0.1 43210 15456\r\n");
+  let response = conn.request("handshake", handshake.clone()).unwrap();
+  let body: zeronet_protocol::templates::Handshake = response.body().unwrap();
+  assert_eq!(body.target_address, Some("1.2.3.4".to_string()));
+
+  // Connections from a trusted proxy without a header are dropped
+  let mut conn = connect(b"");
+  assert!(conn.request("handshake", handshake).is_err());
+}
+
+#[test]
+fn test_denylist() {
+  use crate::denylist::Denylist;
+  use zeronet_peerdb::Hash;
+
+  let denylist = Denylist::parse(
+    "# Abusive ranges
+    10.1.0.0/16
+    2001:db8::/32 # documentation
+    192.0.2.7
+
+    0707070707070707070707070707070707070707070707070707070707070707
+    CAgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAg=",
+  )
+  .unwrap();
+
+  assert!(denylist.denies_ip("10.1.200.3".parse().unwrap()));
+  assert!(denylist.denies_ip("::ffff:10.1.0.1".parse().unwrap()));
+  assert!(!denylist.denies_ip("10.2.0.1".parse().unwrap()));
+  assert!(denylist.denies_ip("2001:db8:1::1".parse().unwrap()));
+  assert!(denylist.denies_ip("192.0.2.7".parse().unwrap()));
+  assert!(!denylist.denies_ip("192.0.2.8".parse().unwrap()));
+  assert!(denylist.denies_hash(&Hash(vec![7; 32])));
+  assert!(denylist.denies_hash(&Hash(vec![8; 32])));
+  assert!(!denylist.denies_hash(&Hash(vec![9; 32])));
+
+  assert!(Denylist::parse("10.0.0.0/33").is_err());
+  assert!(Denylist::parse("not an entry!").is_err());
+  // Hashes of other lengths could never match and are skipped
+  let skipped = Denylist::parse("0a0a0a0a\nCwsLCwsLCwsLCwsLCwsLCw==").unwrap();
+  assert!(!skipped.denies_hash(&Hash(vec![10; 4])));
+  assert!(!skipped.denies_hash(&Hash(vec![11; 16])));
+}
+
+#[test]
+fn test_bittorrent_denylist() {
+  use crate::bittorrent::{self, AnnounceRequest, TrackerConfig};
+  use crate::denylist::Denylist;
+  use zeronet_peerdb::Hash;
+
+  let args = get_arguments_from(vec!["zeronet_tracker", "--allow_addresses", "loopback"]);
+  let shared_state = RwLock::new(SharedState::new(&args));
+  // The trackers share the denylist of the listener, reloads included
+  let handler_config = HandlerConfig::from(&args);
+  let config = TrackerConfig::new(&args, handler_config.denylist.clone());
+  *handler_config.denylist.write().unwrap() =
+    Denylist::parse(&format!("127.0.0.2\n{}", "09".repeat(20))).unwrap();
+
+  let request = |info_hash: u8| AnnounceRequest {
+    info_hash: Hash(vec![info_hash; 20]),
+    port:      6881,
+    event:     None,
+    numwant:   None,
+  };
+  let (local, denied) = ("127.0.0.1".parse().unwrap(), "127.0.0.2".parse().unwrap());
+  for (ip, info_hash) in [(local, 7), (denied, 8), (local, 9)] {
+    bittorrent::announce(&shared_state, &config, ip, &request(info_hash)).unwrap();
+  }
+  {
+    let peer_db = &shared_state::read(&shared_state).peer_db;
+    assert_eq!(peer_db.get_peer_count().unwrap(), 1);
+    assert_eq!(peer_db.get_hash_count().unwrap(), 1);
+    // Denied hashes are not answered either, even when stored
+    peer_db.update_peer(&ipv4_peers(1)[0], &[Hash(vec![9; 20])]).unwrap();
+  }
+  let hashes = vec![Hash(vec![7; 20]), Hash(vec![9; 20])];
+  let counts = bittorrent::scrape(&shared_state, &config, local, &hashes).unwrap();
+  assert_eq!(counts, vec![1, 0]);
+  let counts = bittorrent::scrape(&shared_state, &config, denied, &hashes).unwrap();
+  assert_eq!(counts, vec![0, 0]);
+  let swarm = bittorrent::announce(&shared_state, &config, local, &request(9)).unwrap();
+  assert_eq!(swarm.size, 0);
+  let swarm = bittorrent::announce(&shared_state, &config, denied, &request(7)).unwrap();
+  assert_eq!(swarm.size, 0);
+}
+
+#[test]
+fn test_denylist_reload() {
+  use crate::denylist::{self, Denylist};
+
+  let path = std::env::temp_dir().join(format!("zn_tracker_denylist_{}", std::process::id()));
+  std::fs::write(&path, "10.0.0.1\n").unwrap();
+  let list = Arc::new(RwLock::new(Denylist::load(&path).unwrap()));
+  let (trigger, shutdown) = shutdown::channel();
+  let moved_list = list.clone();
+  let moved_path = path.clone();
+  let watcher = std::thread::spawn(move || {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let interval = Duration::from_millis(50);
+    runtime.block_on(denylist::watch(moved_list, moved_path, interval, shutdown));
+  });
+  let denies = |ip: &str| list.read().unwrap().denies_ip(ip.parse().unwrap());
+  let wait_until = |check: &dyn Fn() -> bool| {
+    for _ in 0..100 {
+      if check() {
+        return true;
+      }
+      std::thread::sleep(Duration::from_millis(50));
+    }
+    false
+  };
+  assert!(denies("10.0.0.1"));
+
+  // Some filesystems only store modification times in whole seconds
+  std::thread::sleep(Duration::from_millis(1100));
+  std::fs::write(&path, "10.0.0.2\n").unwrap();
+  assert!(wait_until(&|| denies("10.0.0.2") && !denies("10.0.0.1")));
+
+  // A broken file keeps the previous list
+  std::thread::sleep(Duration::from_millis(1100));
+  std::fs::write(&path, "10.0.0.3/40\n").unwrap();
+  std::thread::sleep(Duration::from_millis(300));
+  assert!(denies("10.0.0.2"));
+
+  trigger.send(true).unwrap();
+  watcher.join().unwrap();
+  std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_denied_hashes_and_connections() {
+  let path = std::env::temp_dir().join(format!("zn_tracker_denied_{}", std::process::id()));
+  std::fs::write(&path, format!("{}\n", "05".repeat(32))).unwrap();
+  let denylist = path.to_str().unwrap();
+  start_tracker_with_args(15457, &["--allow_addresses", "loopback", "--denylist", denylist]);
+
+  let announce = serde_json::json!({
+    "hashes": [vec![5u8; 32], vec![6u8; 32]],
+    "port": 15441,
+    "need_types": ["ipv4"],
+    "need_num": 20,
+    "add": ["ipv4"]
+  });
+  let address = PeerAddr::parse("127.0.0.1:15457".to_string()).unwrap();
+  let mut conn = Client::from_address(address);
+  let response = conn.request("announce", announce).unwrap();
+  let body: serde_json::Value = response.body().unwrap();
+  // The denied hash keeps its position but gets no peers
+  assert_eq!(body["peers"][0]["ipv4"], serde_json::Value::Null);
+  assert_eq!(body["peers"][1]["ipv4"].as_array().unwrap().len(), 1);
+
+  std::fs::write(&path, "127.0.0.0/8\n").unwrap();
+  start_tracker_with_args(15458, &["--denylist", denylist]);
+  let address = PeerAddr::parse("127.0.0.1:15458".to_string()).unwrap();
+  let mut conn = Client::from_address(address);
+  assert!(conn.request("handshake", handshake()).is_err());
+  std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_allowlist() {
+  use crate::allowlist::{self, Allowlist};
+  use crate::bittorrent::{self, AnnounceRequest, TrackerConfig};
+  use zeronet_peerdb::Hash;
+
+  // ZeroNet clients announce sites with the SHA-256 of their address,
+  // BitTorrent clients with the SHA-1
+  let site = allowlist::parse_entry("1HeLLo4uzjaLetFx6NH3PMwFP3qbRbTf3D").unwrap();
+  let hex = "f69941233e191d9e00f0cd16c5da10b0124d1c0a498b5ecfa1448b21a3eb0094";
+  let info_hash = "86d65bf5a8dea3ebe1965cfe690667255d90647e";
+  let expected = [hex, info_hash].map(|hash| allowlist::parse_entry(hash).unwrap());
+  assert_eq!(site, expected.concat());
+  assert!(allowlist::parse_entry("1Invalid0Address").is_none());
+
+  let public = Allowlist::default();
+  assert!(!public.is_private() && public.permits(&Hash(vec![1; 32])));
+  let private = Allowlist::new(site.clone());
+  assert!(private.is_private() && site.iter().all(|hash| private.permits(hash)));
+  assert!(!private.permits(&Hash(vec![1; 32])));
+
+  // BitTorrent announces of unlisted info hashes are not stored either
+  let args = get_arguments_from(vec![
+    "zeronet_tracker",
+    "--allow_addresses",
+    "loopback",
+    "--allowlist",
+    &"07".repeat(20),
+  ]);
+  let shared_state = RwLock::new(SharedState::new(&args));
+  let config = TrackerConfig::from(&args);
+  let local = "127.0.0.1".parse().unwrap();
+  for info_hash in [Hash(vec![7; 20]), Hash(vec![8; 20])] {
+    let request = AnnounceRequest {
+      info_hash,
+      port: 6881,
+      event: None,
+      numwant: None,
+    };
+    bittorrent::announce(&shared_state, &config, local, &request).unwrap();
+  }
+  // Nor scraped, even when stored
+  shared_state::read(&shared_state)
+    .peer_db
+    .update_peer(&ipv4_peers(1)[0], &[Hash(vec![8; 20])])
+    .unwrap();
+  let hashes = vec![Hash(vec![7; 20]), Hash(vec![8; 20])];
+  let counts = bittorrent::scrape(&shared_state, &config, local, &hashes).unwrap();
+  assert_eq!(counts, vec![1, 0]);
+}
+
+#[test]
+fn test_private_tracker() {
+  let allowlist = "05".repeat(32);
+  start_tracker_with_args(15459, &["--allow_addresses", "loopback", "--allowlist", &allowlist]);
+
+  let announce = serde_json::json!({
+    "hashes": [vec![6u8; 32], vec![5u8; 32]],
+    "port": 15441,
+    "need_types": ["ipv4"],
+    "need_num": 20,
+    "add": ["ipv4"]
+  });
+  let address = PeerAddr::parse("127.0.0.1:15459".to_string()).unwrap();
+  let mut conn = Client::from_address(address);
+  let response = conn.request("announce", announce).unwrap();
+  let body: serde_json::Value = response.body().unwrap();
+  // The unlisted hash keeps its position but gets no peers
+  assert_eq!(body["peers"][0]["ipv4"], serde_json::Value::Null);
+  assert_eq!(body["peers"][1]["ipv4"].as_array().unwrap().len(), 1);
+}
+
+#[test]
+fn test_announce_limits() {
+  start_tracker_with_args(
+    15460,
+    &["--allow_addresses", "loopback", "--max_announce_hashes", "2", "--max_peer_hashes", "3"],
+  );
+  let address = PeerAddr::parse("127.0.0.1:15460".to_string()).unwrap();
+  let mut conn = Client::from_address(address.clone());
+  let announce = |conn: &mut Client, hashes: &[u8], onions: &[&str], delete: bool| {
+    let hashes: Vec<Vec<u8>> = hashes.iter().map(|byte| vec![*byte; 32]).collect();
+    let mut announce = serde_json::json!({
+      "hashes": hashes,
       "port": 15441,
       "need_types": ["ipv4"],
       "need_num": 20,
-      "add": ["onion"]
-    }"#;
-  let value = serde_json::from_str(text).unwrap();
-  value
+      "add": ["ipv4"],
+      "delete": delete
+    });
+    if !onions.is_empty() {
+      announce["onions"] = serde_json::json!(onions);
+    }
+    let response = conn.request("announce", announce).unwrap();
+    let body: serde_json::Value = response.body().unwrap();
+    body["error"].as_str().map(String::from)
+  };
+
+  let error = announce(&mut conn, &[1, 2, 3], &[], false);
+  assert_eq!(error.unwrap(), "Too many hashes, at most 2 per announce");
+  let error = announce(&mut conn, &[1, 2], &["onion"], false);
+  assert_eq!(error.unwrap(), "Announced 1 onions for 2 hashes");
+
+  assert_eq!(announce(&mut conn, &[1, 2], &[], false), None);
+  // Rejected announces do not count towards the limit
+  let error = announce(&mut conn, &[3, 4], &[], false);
+  assert_eq!(error.unwrap(), "Too many hashes, at most 3 per peer");
+  // Hashes announced again do not count twice
+  assert_eq!(announce(&mut conn, &[2, 3], &[], false), None);
+  let error = announce(&mut conn, &[4], &[], false);
+  assert_eq!(error.unwrap(), "Too many hashes, at most 3 per peer");
+  // Replacing the hashes of the peer starts counting anew
+  assert_eq!(announce(&mut conn, &[4], &[], true), None);
+  assert_eq!(announce(&mut conn, &[5, 6], &[], false), None);
+
+  // The limit holds for the peer across connections
+  let mut other = Client::from_address(address);
+  let error = announce(&mut other, &[7], &[], false);
+  assert_eq!(error.unwrap(), "Too many hashes, at most 3 per peer");
+  assert_eq!(announce(&mut other, &[6], &[], false), None);
 }
 
 #[test]
-#[cfg(not(feature = "tor"))]
-fn test_handshake_with_onion() {
-  start_tracker();
+fn test_budget_eviction() {
+  use zeronet_peerdb::Hash;
 
-  let address = PeerAddr::parse("127.0.0.1:15442".to_string()).unwrap();
-  let mut conn = ZeroConnection::from_address(address).unwrap();
-  let handshake_future = conn.request("handshake", handshake());
-  let response = block_on(handshake_future).unwrap();
-  assert_eq!(response.to, conn.last_req_id());
-
-  let announce_future = conn.request("announce", announce());
-  let response = block_on(announce_future).unwrap();
-  assert_eq!(response.to, conn.last_req_id());
+  let hash = |hash: u8| Hash(vec![hash; 32]);
+  // Seen one second apart, the first peer least recently
+  let peers: Vec<Peer> = ipv4_peers(12)
+    .into_iter()
+    .enumerate()
+    .map(|(i, peer)| Peer {
+      last_seen: SystemTime::now() - Duration::from_secs(100 - i as u64),
+      ..peer
+    })
+    .collect();
+  let remaining = |shared_state: &SharedState| {
+    let mut remaining: Vec<u8> = shared_state
+      .peer_db
+      .get_peers()
+      .unwrap()
+      .iter()
+      .map(|peer| match peer.address {
+        PeerAddr::IPV4(ip, _) => ip[3],
+        _ => unreachable!(),
+      })
+      .collect();
+    remaining.sort_unstable();
+    remaining
+  };
+
+  // The oldest peers of a full hash make way, other hashes are left alone
+  let args = get_arguments_from(vec!["zeronet_tracker", "--max_hash_peers", "2"]);
+  let shared_state = SharedState::new(&args);
+  for peer in peers[..4].iter() {
+    shared_state.peer_db.update_peer(peer, &[hash(1)]).unwrap();
+  }
+  shared_state.peer_db.update_peer(&peers[0], &[hash(3)]).unwrap();
+  shared_state.peer_db.update_peer(&peers[4], &[hash(2)]).unwrap();
+  assert_eq!(shared_state.enforce_budget(&[hash(1), hash(2)]).unwrap(), 2);
+  // A peer evicted from one hash is still stored for its others
+  assert_eq!(remaining(&shared_state), vec![0, 2, 3, 4]);
+  let evicted = &peers[0].address;
+  assert_eq!(shared_state.peer_db.get_hashes_for_peer(evicted).unwrap(), vec![hash(3)]);
+  assert_eq!(shared_state.peer_db.get_peers_for_hash(&hash(1)).unwrap().len(), 2);
+
+  // Exceeding the total budget evicts down to 90% of it
+  let args = get_arguments_from(vec!["zeronet_tracker", "--max_total_peers", "10"]);
+  let shared_state = SharedState::new(&args);
+  for peer in peers[..10].iter() {
+    shared_state.peer_db.update_peer(peer, &[hash(1)]).unwrap();
+  }
+  assert_eq!(shared_state.enforce_budget(&[hash(1)]).unwrap(), 0);
+  shared_state.peer_db.update_peer(&peers[10], &[hash(1)]).unwrap();
+  assert_eq!(shared_state.enforce_budget(&[hash(1)]).unwrap(), 2);
+  assert_eq!(remaining(&shared_state), (2..11).collect::<Vec<u8>>());
+
+  // Hashes go away with their last peer
+  let args = get_arguments_from(vec!["zeronet_tracker", "--max_total_hashes", "10"]);
+  let shared_state = SharedState::new(&args);
+  for (i, peer) in peers.iter().enumerate() {
+    shared_state.peer_db.update_peer(peer, &[hash(i as u8)]).unwrap();
+  }
+  shared_state.enforce_budget(&[]).unwrap();
+  assert_eq!(shared_state.peer_db.get_hash_count().unwrap(), 9);
+  assert_eq!(remaining(&shared_state), (3..12).collect::<Vec<u8>>());
+}
+
+#[test]
+fn test_message_scan() {
+  use crate::connection::MessageScan;
+
+  let message_length = |buf: &[u8]| MessageScan::default().advance(buf);
+
+  let message = rmp_serde::to_vec_named(&serde_json::json!({
+    "cmd": "announce",
+    "req_id": 1,
+    "params": {"hashes": [vec![1u8; 300]], "need_num": 20, "add": ["ipv4"]},
+  }))
+  .unwrap();
+  // Incomplete messages wait for more data, whatever was received so far
+  for length in 0..message.len() {
+    assert_eq!(message_length(&message[..length]).unwrap(), None);
+  }
+  let mut two = message.clone();
+  two.extend_from_slice(&message);
+  assert_eq!(message_length(&two).unwrap(), Some(message.len()));
+  assert!(message_length(&[0xc1]).is_err());
+
+  // Scanning picks up where the last read left off
+  let mut scan = MessageScan::default();
+  for length in 0..message.len() {
+    assert_eq!(scan.advance(&message[..length]).unwrap(), None);
+  }
+  assert_eq!(scan.advance(&two).unwrap(), Some(message.len()));
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_idle_connections_share_threads() {
+  fn threads() -> usize {
+    let status = std::fs::read_to_string("/proc/self/status").unwrap();
+    let line = status.lines().find(|line| line.starts_with("Threads:")).unwrap();
+    line[8..].trim().parse().unwrap()
+  }
+
+  start_tracker_with_args(15461, &["--max_connections_per_ip", "0"]);
+  let mut conn = Client::from_address(PeerAddr::parse("127.0.0.1:15461").unwrap());
+  conn.request("ping", serde_json::json!({})).unwrap();
+
+  let before = threads();
+  let idle: Vec<_> = (0..200)
+    .map(|_| std::net::TcpStream::connect("127.0.0.1:15461").unwrap())
+    .collect();
+  std::thread::sleep(Duration::from_millis(500));
+  // Other tests start threads of their own meanwhile, but not one per connection
+  assert!(threads() < before + 100);
+  drop(idle);
 }
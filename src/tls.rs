@@ -0,0 +1,144 @@
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, MsbOption};
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private};
+use openssl::rsa::Rsa;
+use openssl::ssl::{self, ErrorCode, Ssl, SslAcceptor, SslFiletype, SslMethod, SslStream};
+use openssl::x509::{X509NameBuilder, X509};
+use tokio::net::TcpStream;
+
+/// The only encryption scheme the tracker negotiates.
+pub const CRYPT: &str = "tls-rsa";
+
+/// Accepts TLS sessions on peer connections after the handshake
+/// negotiated `tls-rsa`.
+pub struct TlsContext {
+  acceptor: SslAcceptor,
+}
+
+impl TlsContext {
+  /// Uses the given PEM certificate and key,
+  /// or a freshly generated self-signed certificate.
+  pub fn new(cert: Option<PathBuf>, key: Option<PathBuf>) -> Result<TlsContext, ErrorStack> {
+    let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server())?;
+    match (cert, key) {
+      (Some(cert), Some(key)) => {
+        builder.set_certificate_chain_file(cert)?;
+        builder.set_private_key_file(key, SslFiletype::PEM)?;
+      }
+      _ => {
+        let (cert, key) = generate_certificate()?;
+        builder.set_certificate(&cert)?;
+        builder.set_private_key(&key)?;
+      }
+    }
+    builder.check_private_key()?;
+
+    Ok(TlsContext {
+      acceptor: builder.build(),
+    })
+  }
+
+  /// Performs the server side of the TLS handshake.
+  pub async fn accept(&self, stream: TcpStream) -> Result<TlsStream, String> {
+    let ssl = Ssl::new(self.acceptor.context()).map_err(|err| err.to_string())?;
+    let mut stream = SslStream::new(ssl, NonBlocking(stream)).map_err(|err| err.to_string())?;
+    loop {
+      match stream.accept() {
+        Ok(()) => return Ok(TlsStream(stream)),
+        Err(err) => match err.code() {
+          ErrorCode::WANT_READ => stream.get_ref().0.readable().await,
+          ErrorCode::WANT_WRITE => stream.get_ref().0.writable().await,
+          _ => return Err(err.to_string()),
+        }
+        .map_err(|err| err.to_string())?,
+      }
+    }
+  }
+}
+
+fn generate_certificate() -> Result<(X509, PKey<Private>), ErrorStack> {
+  let key = PKey::from_rsa(Rsa::generate(2048)?)?;
+
+  let mut name = X509NameBuilder::new()?;
+  name.append_entry_by_text("CN", "zeronet_tracker")?;
+  let name = name.build();
+
+  let mut serial = BigNum::new()?;
+  serial.rand(64, MsbOption::MAYBE_ZERO, false)?;
+
+  let serial = serial.to_asn1_integer()?;
+  let not_before = Asn1Time::days_from_now(0)?;
+  let not_after = Asn1Time::days_from_now(365)?;
+
+  let mut cert = X509::builder()?;
+  cert.set_version(2)?;
+  cert.set_serial_number(&serial)?;
+  cert.set_subject_name(&name)?;
+  cert.set_issuer_name(&name)?;
+  cert.set_pubkey(&key)?;
+  cert.set_not_before(&not_before)?;
+  cert.set_not_after(&not_after)?;
+  cert.sign(&key, MessageDigest::sha256())?;
+
+  Ok((cert.build(), key))
+}
+
+/// Blocking IO on a tokio socket that fails with `WouldBlock` instead of
+/// waiting, so OpenSSL can run on it without a thread of its own.
+struct NonBlocking(TcpStream);
+
+impl Read for NonBlocking {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    self.0.try_read(buf)
+  }
+}
+
+impl Write for NonBlocking {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    self.0.try_write(buf)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    Ok(())
+  }
+}
+
+/// An encrypted peer connection, waiting on the socket whenever OpenSSL
+/// needs more data or room to write.
+pub struct TlsStream(SslStream<NonBlocking>);
+
+impl TlsStream {
+  /// Waits until the socket is ready for what the failed call needs.
+  async fn ready(&self, err: ssl::Error) -> io::Result<()> {
+    match err.code() {
+      ErrorCode::WANT_READ => self.0.get_ref().0.readable().await,
+      ErrorCode::WANT_WRITE => self.0.get_ref().0.writable().await,
+      _ => Err(err.into_io_error().unwrap_or_else(io::Error::other)),
+    }
+  }
+
+  pub async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    loop {
+      match self.0.ssl_read(buf) {
+        Ok(read) => return Ok(read),
+        Err(err) if err.code() == ErrorCode::ZERO_RETURN => return Ok(0),
+        Err(err) => self.ready(err).await?,
+      }
+    }
+  }
+
+  pub async fn write_all(&mut self, mut buf: &[u8]) -> io::Result<()> {
+    while !buf.is_empty() {
+      match self.0.ssl_write(buf) {
+        Ok(written) => buf = &buf[written..],
+        Err(err) => self.ready(err).await?,
+      }
+    }
+    Ok(())
+  }
+}
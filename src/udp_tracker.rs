@@ -0,0 +1,245 @@
+use std::collections::hash_map::RandomState;
+use std::convert::TryInto;
+use std::hash::{BuildHasher, Hash as _, Hasher};
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::*;
+use tokio::net::UdpSocket;
+use zeronet_peerdb::Hash;
+
+use crate::bittorrent::{self, AnnounceRequest, Event, TrackerConfig, ID_LENGTH};
+#[cfg(feature = "metrics")]
+use crate::metrics;
+use crate::peer_db;
+use crate::shared_state::{self, SharedState};
+use crate::shutdown::Shutdown;
+
+/// Magic constant that connect requests carry in place of a connection ID.
+const PROTOCOL_ID: u64 = 0x41727101980;
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+const HEADER_LENGTH: usize = 16;
+const ANNOUNCE_LENGTH: usize = 98;
+/// Hashes answered per scrape, more would not fit in a single packet.
+const MAX_SCRAPE_HASHES: usize = 74;
+const MAX_PACKET_SIZE: usize = 2048;
+/// Connection IDs stay valid for the window they were issued in and the
+/// next one, so clients can use them for at least a minute as in BEP 15.
+const CONNECTION_ID_WINDOW: u64 = 60;
+
+/// Issues and checks connection IDs without keeping any state, by hashing
+/// the client address and the current time window with a random key.
+pub struct ConnectionIds {
+  key: RandomState,
+}
+
+impl ConnectionIds {
+  pub fn new() -> ConnectionIds {
+    ConnectionIds {
+      key: RandomState::new(),
+    }
+  }
+
+  fn derive(&self, address: &SocketAddr, window: u64) -> u64 {
+    let mut hasher = self.key.build_hasher();
+    address.hash(&mut hasher);
+    window.hash(&mut hasher);
+    hasher.finish()
+  }
+
+  pub fn issue(&self, address: &SocketAddr, now: SystemTime) -> u64 {
+    self.derive(address, window(now))
+  }
+
+  pub fn is_valid(&self, connection_id: u64, address: &SocketAddr, now: SystemTime) -> bool {
+    let window = window(now);
+    connection_id == self.derive(address, window)
+      || connection_id == self.derive(address, window.saturating_sub(1))
+  }
+}
+
+fn window(now: SystemTime) -> u64 {
+  let seconds = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+  seconds / CONNECTION_ID_WINDOW
+}
+
+fn u16_at(packet: &[u8], offset: usize) -> u16 {
+  u16::from_be_bytes(packet[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(packet: &[u8], offset: usize) -> u32 {
+  u32::from_be_bytes(packet[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(packet: &[u8], offset: usize) -> u64 {
+  u64::from_be_bytes(packet[offset..offset + 8].try_into().unwrap())
+}
+
+fn response_header(action: u32, transaction_id: u32) -> Vec<u8> {
+  let mut response = Vec::with_capacity(MAX_PACKET_SIZE);
+  response.extend(action.to_be_bytes());
+  response.extend(transaction_id.to_be_bytes());
+  response
+}
+
+fn error_response(transaction_id: u32, message: &str) -> Vec<u8> {
+  let mut response = response_header(ACTION_ERROR, transaction_id);
+  response.extend(message.as_bytes());
+  response
+}
+
+fn database_error(from: &SocketAddr, transaction_id: u32, err: peer_db::Error) -> Vec<u8> {
+  error!("Database error on UDP request from {}: {:?}", from, err);
+  #[cfg(feature = "metrics")]
+  metrics::DATABASE_ERRORS.inc();
+  error_response(transaction_id, "Internal database error")
+}
+
+fn parse_announce(packet: &[u8]) -> Result<AnnounceRequest, &'static str> {
+  let event = match u32_at(packet, 80) {
+    0 => None,
+    1 => Some(Event::Completed),
+    2 => Some(Event::Started),
+    3 => Some(Event::Stopped),
+    _ => return Err("Invalid event"),
+  };
+  // The IP field at offset 84 is ignored, peers are always stored with
+  // the address the packet came from.
+  let numwant = match u32_at(packet, 92) as i32 {
+    numwant if numwant > 0 => Some(numwant as usize),
+    _ => None,
+  };
+
+  Ok(AnnounceRequest {
+    info_hash: Hash(packet[16..16 + ID_LENGTH].to_vec()),
+    port:      u16_at(packet, 96),
+    event,
+    numwant,
+  })
+}
+
+/// Answers a single BEP 15 packet. Returns `None` for packets that do not
+/// warrant a response, such as ones too short to carry a transaction ID.
+pub fn handle_packet(
+  shared_state: &RwLock<SharedState>,
+  config: &TrackerConfig,
+  connection_ids: &ConnectionIds,
+  packet: &[u8],
+  from: SocketAddr,
+  now: SystemTime,
+) -> Option<Vec<u8>> {
+  if packet.len() < HEADER_LENGTH {
+    return None;
+  }
+  let connection_id = u64_at(packet, 0);
+  let action = u32_at(packet, 8);
+  let transaction_id = u32_at(packet, 12);
+
+  if action == ACTION_CONNECT {
+    if connection_id != PROTOCOL_ID {
+      return None;
+    }
+    let mut response = response_header(ACTION_CONNECT, transaction_id);
+    response.extend(connection_ids.issue(&from, now).to_be_bytes());
+    return Some(response);
+  }
+
+  if !connection_ids.is_valid(connection_id, &from, now) {
+    trace!("Invalid connection ID from {}", from);
+    return Some(error_response(transaction_id, "Invalid connection ID"));
+  }
+
+  let response = match action {
+    ACTION_ANNOUNCE => {
+      if packet.len() < ANNOUNCE_LENGTH {
+        return Some(error_response(transaction_id, "Malformed announce"));
+      }
+      let request = match parse_announce(packet) {
+        Ok(request) => request,
+        Err(message) => return Some(error_response(transaction_id, message)),
+      };
+      trace!("UDP announce from {}: {:?}", from, request);
+
+      match bittorrent::announce(shared_state, config, from.ip(), &request) {
+        Ok(swarm) => {
+          let mut response = response_header(ACTION_ANNOUNCE, transaction_id);
+          response.extend(config.interval.to_be_bytes());
+          response.extend(0u32.to_be_bytes());
+          // ZeroNet peers serve the complete site, so they count as seeders
+          response.extend((swarm.size as u32).to_be_bytes());
+          // Peers are listed in the address family of the client
+          let ipv6 = from.ip().to_canonical().is_ipv6();
+          response.extend(bittorrent::pack_peers(&swarm.peers, ipv6));
+          response
+        }
+        Err(err) => database_error(&from, transaction_id, err),
+      }
+    }
+    ACTION_SCRAPE => {
+      let hashes: Vec<Hash> = packet[HEADER_LENGTH..]
+        .chunks_exact(ID_LENGTH)
+        .take(MAX_SCRAPE_HASHES)
+        .map(|hash| Hash(hash.to_vec()))
+        .collect();
+
+      match bittorrent::scrape(shared_state, config, from.ip(), &hashes) {
+        Ok(counts) => {
+          let mut response = response_header(ACTION_SCRAPE, transaction_id);
+          for count in counts {
+            response.extend((count as u32).to_be_bytes());
+            response.extend(0u32.to_be_bytes());
+            response.extend(0u32.to_be_bytes());
+          }
+          response
+        }
+        Err(err) => database_error(&from, transaction_id, err),
+      }
+    }
+    _ => error_response(transaction_id, "Unknown action"),
+  };
+  Some(response)
+}
+
+pub async fn run(
+  shared_state: Arc<RwLock<SharedState>>,
+  socket: UdpSocket,
+  config: TrackerConfig,
+  mut shutdown: Shutdown,
+) {
+  let config = Arc::new(config);
+  let connection_ids = Arc::new(ConnectionIds::new());
+  let mut buf = [0; MAX_PACKET_SIZE];
+  loop {
+    let received = tokio::select! {
+      received = socket.recv_from(&mut buf) => received,
+      _ = shutdown.triggered() => {
+        info!("Stopping UDP tracker");
+        return;
+      }
+    };
+    let (len, from) = match received {
+      Ok(received) => received,
+      Err(err) => {
+        error!("Could not receive UDP packet: {:?}", err);
+        continue;
+      }
+    };
+
+    let now = SystemTime::now();
+    let packet = buf[..len].to_vec();
+    let (config, connection_ids) = (config.clone(), connection_ids.clone());
+    let response = shared_state::blocking(&shared_state, move |shared_state| {
+      handle_packet(shared_state, &config, &connection_ids, &packet, from, now)
+    })
+    .await;
+    if let Some(response) = response {
+      if let Err(err) = socket.send_to(&response, from).await {
+        warn!("Could not respond to UDP request from {}: {:?}", from, err);
+      }
+    }
+  }
+}